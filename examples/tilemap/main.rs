@@ -4,6 +4,7 @@ extern crate derivative;
 extern crate genmesh;
 extern crate tiled;
 
+use amethyst::assets::{AssetStorage, Loader};
 use amethyst::core::{Transform, TransformBundle};
 use amethyst::prelude::*;
 use amethyst::renderer::{
@@ -19,14 +20,31 @@ impl SimpleState for PlayState {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
         let world = data.world;
         world.register::<TilemapDimensions>();
-        world.register::<TilesheetDimensions>();
+        world.register::<Tilesheets>();
         world.register::<TilemapLayer>();
+        world.register::<AnimatedTiles>();
+        world.register::<FogOfWar>();
+        world.register::<TilemapChunk>();
+        world.register::<TiledObject>();
+        world.register::<ObjectLayer>();
+        world.register::<MapProperties>();
         initialise_camera(world);
-        initialise_tilemap(
-            world,
-            format!("{}/examples/tilemap/resources", application_root_dir()).as_str(),
-            "map.tmx",
-        );
+
+        if !world.res.has_value::<TsxCache>() {
+            world.add_resource(TsxCache::default());
+        }
+        let tsx_cache = TsxCache::clone(&world.read_resource::<TsxCache>());
+        let tilemap = {
+            let loader = world.read_resource::<Loader>();
+            loader.load(
+                "map.tmx",
+                TmxFormat,
+                tsx_cache,
+                (),
+                &world.read_resource::<AssetStorage<Tilemap>>(),
+            )
+        };
+        world.create_entity().with(tilemap).build();
     }
 
     fn handle_event(
@@ -71,6 +89,7 @@ fn run() -> Result<(), amethyst::Error> {
         )
     };
     let game_data = GameDataBuilder::default()
+        .with_bundle(TilemapBundle)?
         .with_bundle(TransformBundle::new())?
         .with_bundle(RenderBundle::new(pipe, Some(config)))?;
     let mut game = Application::build(format!("{}/examples/tilemap/resources", root), PlayState)?
@@ -1,16 +1,24 @@
 #version 150 core
 
-uniform sampler2D TilesheetTexture;
+uniform sampler2D TilesheetTexture0;
+uniform sampler2D TilesheetTexture1;
+uniform sampler2D TilesheetTexture2;
+uniform sampler2D TilesheetTexture3;
 
-const int TILEMAP_BUF_LENGTH = 4096;
+// One texel per tile of the chunk, in row order starting with the top row
+uniform sampler2D TileData;
 
-layout (std140) uniform TileMapBuffer {
-    vec4 u_Data[TILEMAP_BUF_LENGTH];
-};
+const int MAX_TILESHEETS = 4;
 
+// (chunk width, chunk height, brightness of remembered cells, brightness of hidden cells)
 layout (std140) uniform FragmentArgs {
     vec4 u_WorldSize;
-    vec4 u_TilesheetSize;
+};
+
+// Two entries per tilesheet, all in pixels:
+// (image width, image height, tile width, tile height) and (margin, spacing, unused, unused)
+layout (std140) uniform TilesheetBuffer {
+    vec4 u_TilesheetLayout[MAX_TILESHEETS * 2];
 };
 
 in VertexData {
@@ -22,6 +30,18 @@ in VertexData {
 
 out vec4 Color;
 
+// Samplers can't be indexed dynamically in GLSL 1.50, so pick the tilesheet by hand.
+vec4 sampleTilesheet(int sheet, vec2 uvCoords) {
+    if (sheet == 1) {
+        return texture(TilesheetTexture1, uvCoords);
+    } else if (sheet == 2) {
+        return texture(TilesheetTexture2, uvCoords);
+    } else if (sheet == 3) {
+        return texture(TilesheetTexture3, uvCoords);
+    }
+    return texture(TilesheetTexture0, uvCoords);
+}
+
 void main() {
 
     vec2 texCoord = vertex.tex_coord;
@@ -29,24 +49,48 @@ void main() {
     // base coordinates for the charmap tile of the "nearest" (left/down) vertex.
     vec2 bufTileCoords = floor(texCoord);
 
-    // "raw" offset, expressed as 0.0..1.0, for the offset position of the current
-    // fragment
-    // need to invert y-coordinates
-    vec2 rawUvOffsets = vec2(texCoord.x - bufTileCoords.x, 1.0 - (texCoord.y - bufTileCoords.y));
+    // offset, expressed as 0.0..1.0, of the current fragment inside its tile.
+    // y points down, like in Tiled.
+    vec2 tileOffsets = texCoord - bufTileCoords;
 
     vec4 texData;
 
     if (bufTileCoords.x >= 0.0 && bufTileCoords.x < u_WorldSize.x && bufTileCoords.y >= 0.0 && bufTileCoords.y < u_WorldSize.y) {
-        int bufIdx = int((bufTileCoords.y * u_WorldSize.x) + bufTileCoords.x);
-
-        vec4 entry = u_Data[bufIdx];
+        vec4 entry = texelFetch(TileData, ivec2(bufTileCoords), 0);
 
         if (entry.x < 0.0) {
             discard;
         }
 
-        vec2 uvCoords = (entry.xy + rawUvOffsets) / u_TilesheetSize.xy;
-        texData = texture(TilesheetTexture, uvCoords);
+        // the w channel holds the flip flags of the tile: 1 horizontal, 2 vertical, 4 diagonal,
+        // and the fog of war above them: 0 visible, 1 remembered, 2 hidden.
+        // Tiled flips diagonally first, so undo the flips in reverse order.
+        int flags = int(entry.w) & 7;
+        int fog = int(entry.w) / 8;
+        if ((flags & 2) != 0) {
+            tileOffsets.y = 1.0 - tileOffsets.y;
+        }
+        if ((flags & 1) != 0) {
+            tileOffsets.x = 1.0 - tileOffsets.x;
+        }
+        if ((flags & 4) != 0) {
+            tileOffsets = tileOffsets.yx;
+        }
+
+        // the z channel holds the index of the tilesheet the tile is drawn from
+        int sheet = int(entry.z);
+        vec4 sheetSize = u_TilesheetLayout[sheet * 2];
+        vec4 sheetSpacing = u_TilesheetLayout[sheet * 2 + 1];
+
+        // pixel position in the tilesheet, from its top left corner
+        vec2 pixel = sheetSpacing.xx + entry.xy * (sheetSize.zw + sheetSpacing.yy) + tileOffsets * sheetSize.zw;
+        vec2 uvCoords = vec2(pixel.x / sheetSize.x, 1.0 - pixel.y / sheetSize.y);
+        texData = sampleTilesheet(sheet, uvCoords);
+        if (fog == 1) {
+            texData.rgb *= u_WorldSize.z;
+        } else if (fog == 2) {
+            texData.rgb *= u_WorldSize.w;
+        }
     } else {
         discard;
     }
@@ -1 +1,2 @@
-pub mod sprite;
\ No newline at end of file
+pub mod sprite;
+pub mod tilemap;
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use amethyst::animation::{
     Animation, AnimationPrefab, AnimationSet, InterpolationFunction, Sampler, SpriteRenderChannel,
     SpriteRenderPrimitive,
@@ -5,7 +7,7 @@ use amethyst::animation::{
 use amethyst::assets::{AssetStorage, Handle, Loader, PrefabData, PrefabError, ProgressCounter};
 use amethyst::core::specs::prelude::Read;
 use amethyst::core::Transform;
-use amethyst::ecs::{Entity, ReadExpect, WriteStorage};
+use amethyst::ecs::{Component, DenseVecStorage, Entity, ReadExpect, ReadStorage, WriteStorage};
 use amethyst::renderer::{
     PngFormat, Sprite, SpriteRender, SpriteSheet, SpriteSheetHandle, Texture, TextureMetadata,
 };
@@ -58,6 +60,13 @@ pub struct AnimatedSpritePrefab {
     pub spritesheet_png_path: String,
     pub sprite_positions: SerializedSpriteSheet,
     pub animations: Vec<SpriteAnimationData>,
+    /// Index of the sprite shown before any animation runs
+    #[serde(default = "default_sprite_number")]
+    pub sprite_number: usize,
+    /// Keeps the `Transform` the entity already has instead of placing it at the origin,
+    /// set by the override of map objects
+    #[serde(default)]
+    pub keep_transform: bool,
 
     #[serde(skip, default = "default_spritesheet_handle")]
     spritesheet_handle: Option<SpriteSheetHandle>,
@@ -66,6 +75,10 @@ pub struct AnimatedSpritePrefab {
     animation_handles: Vec<(u64, Handle<Animation<SpriteRender>>)>,
 }
 
+fn default_sprite_number() -> usize {
+    1
+}
+
 fn default_spritesheet_handle() -> Option<SpriteSheetHandle> {
     None
 }
@@ -74,6 +87,15 @@ fn default_animation_handle() -> Vec<(u64, Handle<Animation<SpriteRender>>)> {
     Vec::new()
 }
 
+/// Changes made to an `AnimatedSpritePrefab` for the entity holding this, before the prefab is
+/// added to it
+#[derive(Clone)]
+pub struct AnimatedSpritePrefabOverride(pub Arc<dyn Fn(&mut AnimatedSpritePrefab) + Send + Sync>);
+
+impl Component for AnimatedSpritePrefabOverride {
+    type Storage = DenseVecStorage<Self>;
+}
+
 impl AnimatedSpritePrefab {
     fn load_sprite_sheet(
         &mut self,
@@ -175,6 +197,7 @@ impl<'a> PrefabData<'a> for AnimatedSpritePrefab {
         WriteStorage<'a, AnimationSet<u64, Transform>>,
         WriteStorage<'a, AnimationSet<u64, SpriteRender>>,
         <AnimationPrefab<Transform> as PrefabData<'a>>::SystemData,
+        ReadStorage<'a, AnimatedSpritePrefabOverride>,
     );
 
     type Result = ();
@@ -193,17 +216,32 @@ impl<'a> PrefabData<'a> for AnimatedSpritePrefab {
             ref mut animation_set_store,
             ref mut sprite_render_animation_set_store,
             ref mut transform_animation_prefab_system_data,
+            ref override_store,
         ): &mut Self::SystemData,
         entities: &[Entity],
     ) -> Result<(), PrefabError> {
-        let mut transform = Transform::default();
-        transform.set_x(0.0);
-        transform.set_y(0.0);
-        transform_store.insert(entity, transform)?;
+        // Apply the changes of an override to a copy of the prefab, whose sub assets are loaded.
+        let overridden;
+        let prefab = match override_store.get(entity) {
+            Some(AnimatedSpritePrefabOverride(apply)) => {
+                let mut prefab = self.clone();
+                apply(&mut prefab);
+                overridden = prefab;
+                &overridden
+            }
+            None => self,
+        };
+
+        if !prefab.keep_transform || !transform_store.contains(entity) {
+            let mut transform = Transform::default();
+            transform.set_x(0.0);
+            transform.set_y(0.0);
+            transform_store.insert(entity, transform)?;
+        }
 
         let sprite = SpriteRender {
-            sprite_sheet: self.spritesheet_handle.as_ref().cloned().unwrap(),
-            sprite_number: 1,
+            sprite_sheet: prefab.spritesheet_handle.as_ref().cloned().unwrap(),
+            sprite_number: prefab.sprite_number,
         };
         sprite_render_store.insert(entity, sprite)?;
 
@@ -212,7 +250,7 @@ impl<'a> PrefabData<'a> for AnimatedSpritePrefab {
             .unwrap()
             .or_insert_with(AnimationSet::default);
 
-        for animation_handle in &self.animation_handles {
+        for animation_handle in &prefab.animation_handles {
             sprite_render_animation_set
                 .insert(animation_handle.0.clone(), animation_handle.1.clone());
         }
@@ -222,7 +260,7 @@ impl<'a> PrefabData<'a> for AnimatedSpritePrefab {
             .unwrap()
             .or_insert_with(AnimationSet::default);
 
-        for animation in &self.animations {
+        for animation in &prefab.animations {
             if let SpriteAnimationData::Transform {
                 id,
                 animation_prefab,
@@ -255,6 +293,7 @@ impl<'a> PrefabData<'a> for AnimatedSpritePrefab {
             ref mut _animation_set_store,
             ref mut _sprite_render_animation_set_store,
             ref mut transform_animation_prefab_system_data,
+            ref _override_store,
         ): &mut Self::SystemData,
     ) -> Result<bool, PrefabError> {
         self.load_sprite_sheet(loader, sprite_sheet_store, texture_store);
@@ -0,0 +1,87 @@
+use amethyst::assets::{AssetStorage, Loader, PrefabData, PrefabError, ProgressCounter};
+use amethyst::core::specs::prelude::Read;
+use amethyst::core::Transform;
+use amethyst::ecs::{Entity, ReadExpect, WriteStorage};
+use serde::{Deserialize, Serialize};
+
+use crate::tilemap::{Tilemap, TilemapHandle, TilemapLayerFilter, TmxFormat, TsxCache};
+
+/// Prefab for a Tiled map. The layers are spawned by `TilemapSpawnSystem` once the map has
+/// loaded, as children of the prefab entity.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TilemapPrefab {
+    /// Path of the .tmx file, relative to the asset source
+    pub path: String,
+    /// Transform of the whole map
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    /// Names of the layers to spawn, all layers are spawned if `None`
+    #[serde(default)]
+    pub layers: Option<Vec<String>>,
+
+    #[serde(skip, default = "default_tilemap_handle")]
+    tilemap_handle: Option<TilemapHandle>,
+}
+
+fn default_tilemap_handle() -> Option<TilemapHandle> {
+    None
+}
+
+impl<'a> PrefabData<'a> for TilemapPrefab {
+    type SystemData = (
+        ReadExpect<'a, Loader>,
+        Read<'a, AssetStorage<Tilemap>>,
+        WriteStorage<'a, TilemapHandle>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, TilemapLayerFilter>,
+        Read<'a, TsxCache>,
+    );
+
+    type Result = ();
+
+    fn add_to_entity(
+        &self,
+        entity: Entity,
+        (
+            ref _loader,
+            ref _tilemap_store,
+            ref mut tilemap_handle_store,
+            ref mut transform_store,
+            ref mut layer_filter_store,
+            ref _tsx_cache,
+        ): &mut Self::SystemData,
+        _entities: &[Entity],
+    ) -> Result<(), PrefabError> {
+        transform_store.insert(entity, self.transform.clone().unwrap_or_default())?;
+
+        if let Some(ref layers) = self.layers {
+            layer_filter_store.insert(entity, TilemapLayerFilter(layers.clone()))?;
+        }
+
+        let tilemap_handle = self.tilemap_handle.as_ref().cloned().unwrap();
+        tilemap_handle_store.insert(entity, tilemap_handle)?;
+        Ok(())
+    }
+
+    fn load_sub_assets(
+        &mut self,
+        progress: &mut ProgressCounter,
+        (
+            ref loader,
+            ref tilemap_store,
+            ref mut _tilemap_handle_store,
+            ref mut _transform_store,
+            ref mut _layer_filter_store,
+            ref tsx_cache,
+        ): &mut Self::SystemData,
+    ) -> Result<bool, PrefabError> {
+        self.tilemap_handle = Some(loader.load(
+            self.path.as_str(),
+            TmxFormat,
+            TsxCache::clone(tsx_cache),
+            progress,
+            tilemap_store,
+        ));
+        Ok(true)
+    }
+}
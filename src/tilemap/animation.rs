@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use amethyst::core::specs::prelude::{
+    Component, DenseVecStorage, Join, Read, ReadStorage, System, WriteStorage,
+};
+use amethyst::core::timing::Time;
+
+use super::{decode_gid, encode_gid, TilemapLayer, Tilesheets};
+
+/// A frame of a tile animation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnimationFrame {
+    /// Id of the tile shown, in the tileset of the animated tile
+    pub tile_id: u32,
+    /// How long the frame is shown, in milliseconds
+    pub duration: u32,
+}
+
+/// The frame of an animation shown `elapsed` after it started, looping forever like Tiled
+pub fn current_frame(frames: &[AnimationFrame], elapsed: Duration) -> Option<&AnimationFrame> {
+    let total: u64 = frames.iter().map(|frame| u64::from(frame.duration)).sum();
+    if total == 0 {
+        return frames.first();
+    }
+
+    let elapsed = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+    let mut time = elapsed % total;
+    frames.iter().find(|frame| {
+        if time < u64::from(frame.duration) {
+            true
+        } else {
+            time -= u64::from(frame.duration);
+            false
+        }
+    })
+}
+
+/// Cells of a layer showing an animated tile, as indices into `TilemapLayer::tiles`
+#[derive(Clone, Debug, Default)]
+pub struct AnimatedTiles {
+    pub cells: Vec<usize>,
+    /// `TilemapLayer::revision` the cells were found at
+    pub revision: u64,
+}
+
+impl AnimatedTiles {
+    pub fn from_layer(layer: &TilemapLayer, tilesheets: &Tilesheets) -> Self {
+        AnimatedTiles {
+            revision: layer.revision(),
+            cells: layer
+                .gids()
+                .iter()
+                .enumerate()
+                .filter(|(_, gid)| {
+                    tilesheets
+                        .tile_data(**gid)
+                        .map_or(false, |tile| !tile.animation.is_empty())
+                })
+                .map(|(index, _)| index)
+                .collect(),
+        }
+    }
+}
+
+impl Component for AnimatedTiles {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Shows the current frame of every animated tile by rewriting its entry in
+/// `TilemapLayer::tiles`. All animations run on the same clock, so tiles sharing an animation
+/// stay in step. Cells are looked up again whenever tiles of the layer are changed.
+#[derive(Default)]
+pub struct TileAnimationSystem;
+
+impl<'a> System<'a> for TileAnimationSystem {
+    type SystemData = (
+        Read<'a, Time>,
+        ReadStorage<'a, Tilesheets>,
+        WriteStorage<'a, AnimatedTiles>,
+        WriteStorage<'a, TilemapLayer>,
+    );
+
+    fn run(&mut self, (time, tilesheets, mut animated_tiles, mut layers): Self::SystemData) {
+        let elapsed = time.absolute_time();
+        for (tilesheets, animated, layer) in (&tilesheets, &mut animated_tiles, &mut layers).join()
+        {
+            if animated.revision != layer.revision() {
+                *animated = AnimatedTiles::from_layer(layer, tilesheets);
+            }
+            if animated.cells.is_empty() {
+                continue;
+            }
+
+            for &index in &animated.cells {
+                let raw_gid = match layer.gids().get(index) {
+                    Some(raw_gid) => *raw_gid,
+                    None => continue,
+                };
+                let (gid, flip) = decode_gid(raw_gid);
+                let (sheet, tile) = match (tilesheets.sheet_for_gid(gid), tilesheets.tile_data(gid))
+                {
+                    (Some(sheet), Some(tile)) => (sheet, tile),
+                    _ => continue,
+                };
+                if let Some(frame) = current_frame(&tile.animation, elapsed) {
+                    let frame_gid = sheet.dimensions.first_gid + frame.tile_id;
+                    let entry = layer.entry(encode_gid(frame_gid, flip));
+                    layer.set_entry(index, entry);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use amethyst::assets::{
+    Asset, AssetStorage, Error, Format, FormatValue, Handle, ProcessingState, Processor, Result,
+    Source,
+};
+use amethyst::core::bundle::{self, SystemBundle};
+use amethyst::core::specs::prelude::{
+    Component, DenseVecStorage, DispatcherBuilder, Entities, Entity, Join, LazyUpdate, Read,
+    ReadStorage, System, VecStorage,
+};
+use amethyst::core::{GlobalTransform, Transform};
+
+use log::{debug, error};
+
+use super::{parse_map, spawn_tilemap, TileAnimationSystem, TiledMap, TilemapError, TsxCache};
+
+/// A .tmx map loaded through the asset pipeline
+#[derive(Clone, Debug)]
+pub struct Tilemap {
+    /// Path of the map relative to the asset source it was loaded from
+    pub path: String,
+    pub map: TiledMap,
+}
+
+impl Tilemap {
+    /// Directory that tilesheet images are resolved against
+    pub fn directory(&self) -> PathBuf {
+        Path::new(&self.path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+}
+
+pub type TilemapHandle = Handle<Tilemap>;
+
+impl Asset for Tilemap {
+    const NAME: &'static str = "amethyst_extensions::tilemap::Tilemap";
+    type Data = Self;
+    type HandleStorage = VecStorage<TilemapHandle>;
+}
+
+impl From<Tilemap> for Result<ProcessingState<Tilemap>> {
+    fn from(tilemap: Tilemap) -> Result<ProcessingState<Tilemap>> {
+        Ok(ProcessingState::Loaded(tilemap))
+    }
+}
+
+/// Format for loading Tiled .tmx maps.
+/// External tilesets are read through the same asset source as the map and kept in the
+/// `TsxCache` passed as options, so maps sharing it parse each .tsx only once.
+/// ```ignore
+/// let handle = loader.load("map.tmx", TmxFormat, tsx_cache.clone(), &mut progress, &tilemap_storage);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TmxFormat;
+
+impl Format<Tilemap> for TmxFormat {
+    const NAME: &'static str = "TMX";
+
+    type Options = TsxCache;
+
+    fn import(
+        &self,
+        name: String,
+        source: Arc<dyn Source>,
+        tsx_cache: TsxCache,
+        _create_reload: bool,
+    ) -> Result<FormatValue<Tilemap>> {
+        debug!("Loading tilemap {}", name);
+        let bytes = source.load(&name)?;
+        let map = parse_map(&bytes, &name, &tsx_cache, |path| {
+            source
+                .load(path)
+                .map_err(|e| TilemapError::MissingExternalTileset {
+                    path: path.to_owned(),
+                    source: io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+                })
+        })
+        .map_err(|e| Error::from(e.to_string()))?;
+        Ok(FormatValue::data(Tilemap { path: name, map }))
+    }
+}
+
+/// The layer and object entities spawned for an entity holding a `TilemapHandle`.
+/// Empty if the map failed to spawn.
+#[derive(Clone, Debug, Default)]
+pub struct TilemapLayers(pub Vec<Entity>);
+
+impl Component for TilemapLayers {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Restricts the layers spawned for an entity holding a `TilemapHandle` to the named ones.
+#[derive(Clone, Debug, Default)]
+pub struct TilemapLayerFilter(pub Vec<String>);
+
+impl Component for TilemapLayerFilter {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Spawns the layer entities of every entity holding a loaded `TilemapHandle`.
+/// The layers are attached to the entity holding the handle, so moving it moves the whole map.
+#[derive(Default)]
+pub struct TilemapSpawnSystem;
+
+impl<'a> System<'a> for TilemapSpawnSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, AssetStorage<Tilemap>>,
+        ReadStorage<'a, TilemapHandle>,
+        ReadStorage<'a, TilemapLayers>,
+        ReadStorage<'a, TilemapLayerFilter>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, tilemap_storage, handles, spawned, filters, lazy): Self::SystemData,
+    ) {
+        for (entity, handle, _) in (&*entities, &handles, !&spawned).join() {
+            let tilemap = match tilemap_storage.get(handle) {
+                Some(tilemap) => tilemap.clone(),
+                None => continue,
+            };
+            let filter = filters.get(entity).cloned();
+
+            // Spawning needs the loader and the mesh and texture storages, so defer it.
+            lazy.exec_mut(move |world| {
+                if !world.is_alive(entity) {
+                    return;
+                }
+
+                {
+                    let mut transforms = world.write_storage::<Transform>();
+                    if !transforms.contains(entity) {
+                        transforms
+                            .insert(entity, Transform::default())
+                            .expect("Tilemap entity is alive");
+                    }
+                    let mut globals = world.write_storage::<GlobalTransform>();
+                    if !globals.contains(entity) {
+                        globals
+                            .insert(entity, GlobalTransform::default())
+                            .expect("Tilemap entity is alive");
+                    }
+                }
+
+                let layers = match spawn_tilemap(
+                    world,
+                    &tilemap.map,
+                    &tilemap.directory(),
+                    Some(entity),
+                    filter.as_ref().map(|filter| &filter.0[..]),
+                ) {
+                    Ok(layers) => layers,
+                    Err(e) => {
+                        error!("Error while spawning tilemap {}: {}", tilemap.path, e);
+                        Vec::new()
+                    }
+                };
+                world
+                    .write_storage::<TilemapLayers>()
+                    .insert(entity, TilemapLayers(layers))
+                    .expect("Tilemap entity is alive");
+            });
+        }
+    }
+}
+
+/// Adds the `Tilemap` asset processor, the `TilemapSpawnSystem` and the
+/// `TileAnimationSystem`.
+#[derive(Default)]
+pub struct TilemapBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for TilemapBundle {
+    fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> bundle::Result<()> {
+        builder.add(Processor::<Tilemap>::new(), "tilemap_processor", &[]);
+        builder.add(TilemapSpawnSystem, "tilemap_spawn", &["tilemap_processor"]);
+        builder.add(TileAnimationSystem, "tile_animation", &[]);
+        Ok(())
+    }
+}
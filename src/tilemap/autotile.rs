@@ -0,0 +1,446 @@
+use std::marker::PhantomData;
+
+use amethyst::core::specs::prelude::{Component, DenseVecStorage, Join, System, WriteStorage};
+
+use super::{decode_gid, TilemapLayer};
+
+/// Reduced neighbour masks of the 47 blob tiles, in the order their gids are given.
+/// Bits are N = 1, NE = 2, E = 4, SE = 8, S = 16, SW = 32, W = 64 and NW = 128, and a corner
+/// only counts when both edges next to it do.
+pub const BLOB_MASKS: [u8; 47] = [
+    0, 1, 4, 5, 7, 16, 17, 20, 21, 23, 28, 29, 31, 64, 65, 68, 69, 71, 80, 81, 84, 85, 87, 92, 93,
+    95, 112, 113, 116, 117, 119, 124, 125, 127, 193, 197, 199, 209, 213, 215, 221, 223, 241, 245,
+    247, 253, 255,
+];
+
+/// Offsets of the eight neighbours of a cell, clockwise from the top, like the colours of a
+/// Wang id
+const NEIGHBOURS: [(i64, i64); 8] = [
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+];
+
+/// Clears the corner bits of a neighbour mask that aren't next to two set edge bits, giving
+/// one of `BLOB_MASKS`
+fn reduce_blob_mask(mut mask: u8) -> u8 {
+    for corner in [1, 3, 5, 7].iter() {
+        let before = 1 << (corner - 1);
+        let after = 1 << ((corner + 1) % 8);
+        if mask & before == 0 || mask & after == 0 {
+            mask &= !(1 << corner);
+        }
+    }
+    mask
+}
+
+/// A tile of a Wang set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WangTile {
+    pub gid: u32,
+    /// Colours of the edges and corners of the tile, clockwise from the top edge:
+    /// top, top right, right, bottom right, bottom, bottom left, left, top left.
+    /// 0 matches any colour.
+    pub wang_id: [u32; 8],
+}
+
+/// How the tile of a cell is chosen from the terrain around it
+#[derive(Clone, Debug)]
+pub enum AutotileRule<T> {
+    /// Cells of `terrain` get `gids[mask]`, the mask having the bits N = 1, E = 2, S = 4 and
+    /// W = 8 set for the neighbours of the same terrain
+    Edge16 { terrain: T, gids: [u32; 16] },
+    /// Cells of `terrain` get the gid of their reduced neighbour mask, see `BLOB_MASKS`
+    Blob47 { terrain: T, gids: [u32; 47] },
+    /// Cells of a terrain in `colors` get the tile whose Wang id matches best the colours of
+    /// the cells around them: each edge and corner takes the colour of the cell beyond it
+    Wang {
+        tiles: Vec<WangTile>,
+        colors: Vec<(T, u32)>,
+    },
+}
+
+impl<T: PartialEq> AutotileRule<T> {
+    /// 16 tile edge set laid out so that the tile for a mask is `first_gid + mask`
+    pub fn edge16(terrain: T, first_gid: u32) -> Self {
+        let mut gids = [0; 16];
+        for (mask, gid) in gids.iter_mut().enumerate() {
+            *gid = first_gid + mask as u32;
+        }
+        AutotileRule::Edge16 { terrain, gids }
+    }
+
+    /// 47 tile blob set laid out in the order of `BLOB_MASKS`, starting at `first_gid`
+    pub fn blob47(terrain: T, first_gid: u32) -> Self {
+        let mut gids = [0; 47];
+        for (index, gid) in gids.iter_mut().enumerate() {
+            *gid = first_gid + index as u32;
+        }
+        AutotileRule::Blob47 { terrain, gids }
+    }
+
+    /// Terrain a tile stands for, if the rule places it
+    fn terrain_of(&self, gid: u32) -> Option<&T> {
+        match self {
+            AutotileRule::Edge16 { terrain, gids } if gids.contains(&gid) => Some(terrain),
+            AutotileRule::Blob47 { terrain, gids } if gids.contains(&gid) => Some(terrain),
+            AutotileRule::Wang { tiles, colors } => {
+                let tile = tiles.iter().find(|tile| tile.gid == gid)?;
+                // The colour the tile is mostly made of
+                let color = tile
+                    .wang_id
+                    .iter()
+                    .filter(|color| **color != 0)
+                    .max_by_key(|color| {
+                        tile.wang_id.iter().filter(|other| other == color).count()
+                    })?;
+                colors
+                    .iter()
+                    .find(|(_, terrain_color)| terrain_color == color)
+                    .map(|(terrain, _)| terrain)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Logical terrain of every cell of a layer, `None` for cells no rule applies to
+#[derive(Clone, Debug)]
+pub struct TerrainGrid<T> {
+    pub width: u32,
+    pub height: u32,
+    cells: Vec<Option<T>>,
+}
+
+impl<T: Clone + PartialEq> TerrainGrid<T> {
+    pub fn new(width: u32, height: u32) -> Self {
+        TerrainGrid {
+            width,
+            height,
+            cells: vec![None; (width * height) as usize],
+        }
+    }
+
+    /// Grid filled by calling `terrain` for each cell
+    pub fn from_fn<F>(width: u32, height: u32, mut terrain: F) -> Self
+    where
+        F: FnMut(u32, u32) -> Option<T>,
+    {
+        let mut cells = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                cells.push(terrain(x, y));
+            }
+        }
+        TerrainGrid {
+            width,
+            height,
+            cells,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Option<&T> {
+        if x < self.width && y < self.height {
+            self.cells[(y * self.width + x) as usize].as_ref()
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, terrain: Option<T>) {
+        if x < self.width && y < self.height {
+            self.cells[(y * self.width + x) as usize] = terrain;
+        }
+    }
+
+    /// Terrain of the neighbour of a cell at the given offset.
+    /// Cells outside the grid continue the terrain of the cell, so borders don't show.
+    fn neighbour(&self, x: u32, y: u32, (dx, dy): (i64, i64)) -> Option<&T> {
+        let (nx, ny) = (i64::from(x) + dx, i64::from(y) + dy);
+        if nx < 0 || ny < 0 || nx >= i64::from(self.width) || ny >= i64::from(self.height) {
+            self.get(x, y)
+        } else {
+            self.get(nx as u32, ny as u32)
+        }
+    }
+}
+
+/// Chooses the tiles of a layer from a terrain grid, and keeps them matching when tiles of
+/// the layer change. Put it on a layer entity and add an `AutotileSystem` to have edits
+/// made through `TilemapLayer::set_tile` fix up the tiles around them.
+#[derive(Clone, Debug)]
+pub struct Autotiler<T> {
+    pub rules: Vec<AutotileRule<T>>,
+    pub grid: TerrainGrid<T>,
+    /// Gids of the layer when it was last autotiled
+    gids: Vec<u32>,
+    /// `TilemapLayer::revision` the layer was last autotiled at
+    revision: Option<u64>,
+}
+
+impl<T: Clone + PartialEq> Autotiler<T> {
+    pub fn new(grid: TerrainGrid<T>, rules: Vec<AutotileRule<T>>) -> Self {
+        Autotiler {
+            rules,
+            grid,
+            gids: Vec::new(),
+            revision: None,
+        }
+    }
+
+    /// Autotiler whose terrain grid is read from the tiles the rules place in a layer
+    pub fn from_layer(layer: &TilemapLayer, rules: Vec<AutotileRule<T>>) -> Self {
+        let mut autotiler = Autotiler::new(TerrainGrid::new(layer.width, layer.height), rules);
+        let grid = TerrainGrid::from_fn(layer.width, layer.height, |x, y| {
+            autotiler.terrain_of(layer.get_tile(x, y).unwrap_or(0))
+        });
+        autotiler.grid = grid;
+        autotiler
+    }
+
+    /// Terrain a tile stands for according to the rules, flip flags ignored
+    pub fn terrain_of(&self, gid: u32) -> Option<T> {
+        let (gid, _) = decode_gid(gid);
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.terrain_of(gid))
+            .next()
+            .cloned()
+    }
+
+    /// Tile the first rule applying to a cell chooses for it
+    pub fn choose(&self, x: u32, y: u32) -> Option<u32> {
+        let terrain = self.grid.get(x, y)?;
+        self.rules
+            .iter()
+            .filter_map(|rule| self.apply_rule(rule, terrain, x, y))
+            .next()
+    }
+
+    fn apply_rule(&self, rule: &AutotileRule<T>, terrain: &T, x: u32, y: u32) -> Option<u32> {
+        let same = |offset| self.grid.neighbour(x, y, offset) == Some(terrain);
+        match rule {
+            AutotileRule::Edge16 {
+                terrain: rule_terrain,
+                gids,
+            } if rule_terrain == terrain => {
+                let mask = [(0, -1), (1, 0), (0, 1), (-1, 0)]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, offset)| same(**offset))
+                    .fold(0, |mask, (bit, _)| mask | 1 << bit);
+                Some(gids[mask])
+            }
+            AutotileRule::Blob47 {
+                terrain: rule_terrain,
+                gids,
+            } if rule_terrain == terrain => {
+                let mask = NEIGHBOURS
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, offset)| same(**offset))
+                    .fold(0u8, |mask, (bit, _)| mask | 1 << bit);
+                let mask = reduce_blob_mask(mask);
+                let index = BLOB_MASKS.iter().position(|blob| *blob == mask)?;
+                Some(gids[index])
+            }
+            AutotileRule::Wang { tiles, colors } => {
+                let color_of = |terrain: Option<&T>| {
+                    terrain.and_then(|terrain| {
+                        colors
+                            .iter()
+                            .find(|(color_terrain, _)| color_terrain == terrain)
+                            .map(|(_, color)| *color)
+                    })
+                };
+                color_of(Some(terrain))?;
+                let mut wanted = [0; 8];
+                for (color, offset) in wanted.iter_mut().zip(NEIGHBOURS.iter()) {
+                    *color = color_of(self.grid.neighbour(x, y, *offset)).unwrap_or(0);
+                }
+                tiles
+                    .iter()
+                    .map(|tile| {
+                        let score = tile
+                            .wang_id
+                            .iter()
+                            .zip(wanted.iter())
+                            .map(|(color, wanted)| match (*color, *wanted) {
+                                (0, _) | (_, 0) => 1,
+                                (color, wanted) if color == wanted => 2,
+                                _ => 0,
+                            })
+                            .sum::<u32>();
+                        (score, tile.gid)
+                    })
+                    // The first of the best matching tiles
+                    .fold(None, |best: Option<(u32, u32)>, (score, gid)| match best {
+                        Some((best_score, _)) if best_score >= score => best,
+                        _ => Some((score, gid)),
+                    })
+                    .map(|(_, gid)| gid)
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the tile chosen for every cell into the layer. Cells no rule applies to keep
+    /// their tile.
+    pub fn apply(&mut self, layer: &mut TilemapLayer) {
+        for y in 0..self.grid.height {
+            for x in 0..self.grid.width {
+                if let Some(gid) = self.choose(x, y) {
+                    layer.set_tile(x, y, gid);
+                }
+            }
+        }
+        self.sync(layer);
+    }
+
+    /// Changes the terrain of a cell and fixes up its tile and the tiles around it
+    pub fn set_terrain(&mut self, layer: &mut TilemapLayer, x: u32, y: u32, terrain: Option<T>) {
+        self.grid.set(x, y, terrain);
+        self.retile_around(layer, x, y);
+        self.sync(layer);
+    }
+
+    /// Looks for tiles of the layer changed since it was last autotiled, takes their terrain
+    /// from the rules and fixes up the tiles around them.
+    /// Returns whether any tile changed.
+    pub fn update(&mut self, layer: &mut TilemapLayer) -> bool {
+        if self.revision == Some(layer.revision()) {
+            return false;
+        }
+        if self.gids.len() != layer.gids().len() {
+            self.apply(layer);
+            return true;
+        }
+
+        let changed = layer
+            .gids()
+            .iter()
+            .zip(self.gids.iter())
+            .enumerate()
+            .filter(|(_, (gid, previous))| gid != previous)
+            .map(|(index, _)| (index as u32 % layer.width, index as u32 / layer.width))
+            .collect::<Vec<_>>();
+        for &(x, y) in &changed {
+            let terrain = self.terrain_of(layer.get_tile(x, y).unwrap_or(0));
+            self.grid.set(x, y, terrain);
+        }
+        for &(x, y) in &changed {
+            self.retile_around(layer, x, y);
+        }
+        self.sync(layer);
+        !changed.is_empty()
+    }
+
+    fn retile_around(&self, layer: &mut TilemapLayer, x: u32, y: u32) {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (nx, ny) = (i64::from(x) + dx, i64::from(y) + dy);
+                if nx < 0 || ny < 0 {
+                    continue;
+                }
+                if let Some(gid) = self.choose(nx as u32, ny as u32) {
+                    layer.set_tile(nx as u32, ny as u32, gid);
+                }
+            }
+        }
+    }
+
+    fn sync(&mut self, layer: &TilemapLayer) {
+        self.gids = layer.gids().to_vec();
+        self.revision = Some(layer.revision());
+    }
+}
+
+impl<T> Component for Autotiler<T>
+where
+    T: Send + Sync + 'static,
+{
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Keeps the tiles of every layer with an `Autotiler<T>` matching after tiles are changed.
+pub struct AutotileSystem<T> {
+    _pd: PhantomData<T>,
+}
+
+impl<T> Default for AutotileSystem<T> {
+    fn default() -> Self {
+        AutotileSystem { _pd: PhantomData }
+    }
+}
+
+impl<'a, T> System<'a> for AutotileSystem<T>
+where
+    T: Clone + PartialEq + Send + Sync + 'static,
+{
+    type SystemData = (
+        WriteStorage<'a, Autotiler<T>>,
+        WriteStorage<'a, TilemapLayer>,
+    );
+
+    fn run(&mut self, (mut autotilers, mut layers): Self::SystemData) {
+        for (autotiler, layer) in (&mut autotilers, &mut layers).join() {
+            autotiler.update(layer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blob_masks_are_the_reduced_masks() {
+        let mut reduced = (0..=255u8).map(reduce_blob_mask).collect::<Vec<_>>();
+        reduced.sort();
+        reduced.dedup();
+        assert_eq!(reduced, BLOB_MASKS.to_vec());
+    }
+
+    #[test]
+    fn corners_need_both_edges() {
+        // N, NE and E
+        assert_eq!(reduce_blob_mask(1 | 2 | 4), 1 | 2 | 4);
+        // NE without E
+        assert_eq!(reduce_blob_mask(1 | 2), 1);
+        // Every corner without any edge
+        assert_eq!(reduce_blob_mask(2 | 8 | 32 | 128), 0);
+        assert_eq!(reduce_blob_mask(255), 255);
+    }
+
+    #[test]
+    fn blob_tiles_follow_the_neighbours() {
+        const MAP: [&str; 4] = [".....", ".##..", ".###.", "....."];
+        let grid = TerrainGrid::from_fn(5, 4, |x, y| {
+            Some(MAP[y as usize].as_bytes()[x as usize] as char).filter(|c| *c == '#')
+        });
+        let autotiler = Autotiler::new(grid, vec![AutotileRule::blob47('#', 100)]);
+        let gid = |mask| 100 + BLOB_MASKS.iter().position(|blob| *blob == mask).unwrap() as u32;
+
+        // E, SE and S
+        assert_eq!(autotiler.choose(1, 1), Some(gid(4 | 8 | 16)));
+        // SE is dropped without E
+        assert_eq!(autotiler.choose(2, 1), Some(gid(16 | 32 | 64)));
+        // NW is dropped without N
+        assert_eq!(autotiler.choose(3, 2), Some(gid(64)));
+        assert_eq!(autotiler.choose(0, 0), None);
+    }
+
+    #[test]
+    fn borders_continue_the_cell() {
+        let grid = TerrainGrid::from_fn(2, 2, |_, _| Some('#'));
+        let autotiler = Autotiler::new(grid, vec![AutotileRule::blob47('#', 1)]);
+        for &(x, y) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(autotiler.choose(x, y), Some(47));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use amethyst::assets::Loader;
+use amethyst::ecs::Entity;
+use amethyst::prelude::*;
+use amethyst::renderer::{PngFormat, TextureMetadata};
+
+use super::{
+    add_collision, chunk_size, spawn_layer, MapProperties, Properties, TilemapDimensions,
+    TilemapError, TilemapLayer, TilesetData, Tilesheet, TilesheetDimensions, Tilesheets,
+};
+
+/// Builds a tilemap in code instead of loading a .tmx file, for maps generated at runtime.
+/// `spawn` creates the same layer and chunk entities as `spawn_tilemap`, so the layers are
+/// drawn by `DrawTilemap` and work with everything reading `TilemapLayer`.
+/// ```ignore
+/// let layers = TilemapBuilder::new(64, 48, (16, 16))
+///     .tileset("textures/dungeon.png", 8, 8)
+///     .layer("floor", |x, y| if cave.is_wall(x, y) { 2 } else { 1 })
+///     .spawn(world)?;
+/// ```
+pub struct TilemapBuilder {
+    dimensions: TilemapDimensions,
+    /// Image path, layout and data of each tileset
+    tilesets: Vec<(String, TilesheetDimensions, TilesetData)>,
+    /// Name, gids and properties of each layer
+    layers: Vec<(String, Vec<u32>, Properties)>,
+    properties: Properties,
+    parent: Option<Entity>,
+}
+
+impl TilemapBuilder {
+    /// Map of `width` x `height` tiles of `tile_size` pixels
+    pub fn new(width: u32, height: u32, (tile_width, tile_height): (u32, u32)) -> Self {
+        TilemapBuilder {
+            dimensions: TilemapDimensions {
+                width,
+                height,
+                tile_width,
+                tile_height,
+            },
+            tilesets: Vec::new(),
+            layers: Vec::new(),
+            properties: Properties::new(),
+            parent: None,
+        }
+    }
+
+    /// Adds a tilesheet image of `columns` x `rows` tiles the size of the map tiles, without
+    /// margin or spacing. `path` is relative to the asset directory.
+    /// Its gids follow those of the tilesets added before it, the first tileset starting at 1.
+    pub fn tileset(self, path: &str, columns: u32, rows: u32) -> Self {
+        let dimensions = TilesheetDimensions {
+            first_gid: 0,
+            width: columns,
+            height: rows,
+            tile_width: self.dimensions.tile_width,
+            tile_height: self.dimensions.tile_height,
+            margin: 0,
+            spacing: 0,
+            image_width: columns * self.dimensions.tile_width,
+            image_height: rows * self.dimensions.tile_height,
+        };
+        let data = TilesetData {
+            name: path.to_owned(),
+            ..TilesetData::default()
+        };
+        self.tileset_with_data(path, dimensions, data)
+    }
+
+    /// Adds a tilesheet with its own layout, and tile data such as properties read by
+    /// pathfinding, animations or collision shapes. The `first_gid` of `dimensions` is
+    /// ignored, gids follow those of the tilesets added before.
+    pub fn tileset_with_data(
+        mut self,
+        path: &str,
+        mut dimensions: TilesheetDimensions,
+        data: TilesetData,
+    ) -> Self {
+        dimensions.first_gid = self
+            .tilesets
+            .last()
+            .map_or(1, |(_, last, _)| last.first_gid + last.tile_count());
+        self.tilesets.push((path.to_owned(), dimensions, data));
+        self
+    }
+
+    /// Adds a tile layer above those added before, `gid` giving the gid of each cell from its
+    /// column and row, counted from the top left corner. 0 leaves a cell empty.
+    pub fn layer<F>(self, name: &str, gid: F) -> Self
+    where
+        F: FnMut(u32, u32) -> u32,
+    {
+        self.layer_with_properties(name, Properties::new(), gid)
+    }
+
+    /// Adds a tile layer with custom properties
+    pub fn layer_with_properties<F>(
+        mut self,
+        name: &str,
+        properties: Properties,
+        mut gid: F,
+    ) -> Self
+    where
+        F: FnMut(u32, u32) -> u32,
+    {
+        let (width, height) = (self.dimensions.width, self.dimensions.height);
+        let mut gids = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                gids.push(gid(x, y));
+            }
+        }
+        self.layers.push((name.to_owned(), gids, properties));
+        self
+    }
+
+    /// Custom properties of the map, added to every layer as `MapProperties`
+    pub fn properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Attaches the layers to a parent entity, like `Tilemap` does
+    pub fn parent(mut self, parent: Entity) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Creates an entity for each layer, in the order they were added, and adds the collision
+    /// shapes of the tilesets to the `TileCollision` resource. Each layer can have tiles from
+    /// at most `MAX_TILESHEETS` tilesets.
+    /// Returns the created layer entities.
+    pub fn spawn(self, world: &mut World) -> Result<Vec<Entity>, TilemapError> {
+        if self.tilesets.is_empty() {
+            return Err(TilemapError::MissingTileset);
+        }
+
+        let chunk_size = chunk_size(world);
+        let mut tilesheets = Tilesheets::default();
+        for (path, dimensions, data) in self.tilesets {
+            let texture = {
+                let loader = world.read_resource::<Loader>();
+                loader.load(
+                    path.as_str(),
+                    PngFormat,
+                    TextureMetadata::srgb_scale(),
+                    (),
+                    &world.read_resource(),
+                )
+            };
+            tilesheets.sheets.push(Tilesheet {
+                dimensions,
+                image_source: path,
+                source: None,
+                texture,
+                data: Arc::new(data),
+            });
+        }
+
+        add_collision(world, &tilesheets);
+
+        let tilesheet_dimensions = tilesheets.dimensions();
+        let map_properties = MapProperties(self.properties);
+        if let Some(parent) = self.parent {
+            world
+                .write_storage::<MapProperties>()
+                .insert(parent, map_properties.clone())
+                .expect("Tilemap parent is alive");
+        }
+
+        let mut entities = Vec::with_capacity(self.layers.len());
+        for (map_index, (name, gids, properties)) in self.layers.into_iter().enumerate() {
+            let mut tilemap_layer = TilemapLayer::new(
+                &name,
+                self.dimensions.width,
+                self.dimensions.height,
+                gids,
+                tilesheet_dimensions.clone(),
+                chunk_size,
+                properties,
+            );
+            tilemap_layer.map_index = map_index;
+            entities.push(spawn_layer(
+                world,
+                tilemap_layer,
+                &self.dimensions,
+                &tilesheets,
+                &map_properties,
+                self.parent,
+            )?);
+        }
+        Ok(entities)
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use amethyst::core::nalgebra::{Point3, Vector2};
+use amethyst::core::GlobalTransform;
+
+use super::{
+    bounds, decode_gid, TileFlip, TileRegion, TilemapDimensions, TilemapLayer, TilesetData,
+    Tilesheets,
+};
+
+/// Collision shape drawn in Tiled's collision editor, in pixels from the top left corner of
+/// the tile with y pointing down
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileShape {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// Ellipse inscribed in the given rectangle
+    Ellipse {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Polygon(Vec<Vector2<f32>>),
+    Polyline(Vec<Vector2<f32>>),
+    Point(Vector2<f32>),
+}
+
+/// Collision shape of a placed tile in world space
+#[derive(Clone, Debug, PartialEq)]
+pub enum CollisionShape {
+    /// Axis aligned rectangle
+    Rect {
+        min: Vector2<f32>,
+        max: Vector2<f32>,
+    },
+    /// Axis aligned ellipse. Rotation of the layer is not applied to ellipses.
+    Ellipse {
+        center: Vector2<f32>,
+        radii: Vector2<f32>,
+    },
+    Polygon(Vec<Vector2<f32>>),
+    Polyline(Vec<Vector2<f32>>),
+    Point(Vector2<f32>),
+}
+
+/// Collision shapes of every tile that has some, keyed by tileset name and then by the id of
+/// the tile in its tileset. `spawn_tilemap` and `TilemapBuilder` add the shapes of the
+/// tilesets they load, see `TileData::collision`.
+#[derive(Clone, Debug, Default)]
+pub struct TileCollision {
+    tilesets: HashMap<String, HashMap<u32, Vec<TileShape>>>,
+}
+
+impl TileCollision {
+    /// Imports the collision shapes of a tileset, replacing those of a tileset with the same
+    /// name
+    pub fn add_tileset(&mut self, tileset: &TilesetData) {
+        let shapes = tileset
+            .tiles
+            .iter()
+            .filter(|(_, tile)| !tile.collision.is_empty())
+            .map(|(id, tile)| (*id, tile.collision.clone()))
+            .collect();
+        self.tilesets.insert(tileset.name.clone(), shapes);
+    }
+
+    /// Shapes of a tile given its tileset name and id in the tileset
+    pub fn tile_shapes(&self, tileset: &str, id: u32) -> &[TileShape] {
+        self.tilesets
+            .get(tileset)
+            .and_then(|tiles| tiles.get(&id))
+            .map(|shapes| &shapes[..])
+            .unwrap_or(&[])
+    }
+
+    /// Shapes of a tile given its gid, flip flags included
+    pub fn gid_shapes<'a>(&'a self, tilesheets: &Tilesheets, gid: u32) -> &'a [TileShape] {
+        match tilesheets.sheet_for_gid(gid) {
+            Some(sheet) => {
+                let (gid, _) = decode_gid(gid);
+                self.tile_shapes(&sheet.data.name, gid - sheet.dimensions.first_gid)
+            }
+            None => &[],
+        }
+    }
+
+    /// World space collision shapes of every tile of `layer` in `region`.
+    /// `global` is the `GlobalTransform` of the layer entity.
+    pub fn shapes_in_region(
+        &self,
+        layer: &TilemapLayer,
+        dimensions: &TilemapDimensions,
+        tilesheets: &Tilesheets,
+        global: &GlobalTransform,
+        region: TileRegion,
+    ) -> Vec<CollisionShape> {
+        shapes_in_region(layer, dimensions, tilesheets, global, region, |gid| {
+            self.gid_shapes(tilesheets, gid)
+        })
+    }
+}
+
+/// Places the shapes `tile_shapes` gives for the gid of every cell of `region` in the world
+fn shapes_in_region<'a, F>(
+    layer: &TilemapLayer,
+    dimensions: &TilemapDimensions,
+    tilesheets: &Tilesheets,
+    global: &GlobalTransform,
+    region: TileRegion,
+    tile_shapes: F,
+) -> Vec<CollisionShape>
+where
+    F: Fn(u32) -> &'a [TileShape],
+{
+    let mut shapes = Vec::new();
+    for (x, y) in region.cells(layer.width, layer.height) {
+        let gid = layer.get_tile(x, y).unwrap_or(0);
+        let sheet = match tilesheets.sheet_for_gid(gid) {
+            Some(sheet) => sheet,
+            None => continue,
+        };
+        let (_, flip) = decode_gid(gid);
+        let cell = Cell {
+            origin: dimensions.tile_top_left(x, y),
+            cell_size: Vector2::new(dimensions.tile_width as f32, dimensions.tile_height as f32),
+            tile_size: Vector2::new(
+                sheet.dimensions.tile_width as f32,
+                sheet.dimensions.tile_height as f32,
+            ),
+            flip,
+            global,
+        };
+        for shape in tile_shapes(gid) {
+            shapes.push(cell.to_world(shape));
+        }
+    }
+    shapes
+}
+
+/// Places shapes drawn in tilesheet pixels into a cell of a layer
+struct Cell<'a> {
+    /// Top left corner of the cell, relative to the centre of the layer
+    origin: Vector2<f32>,
+    cell_size: Vector2<f32>,
+    /// Size of the tiles of the tilesheet the shapes were drawn on
+    tile_size: Vector2<f32>,
+    flip: TileFlip,
+    global: &'a GlobalTransform,
+}
+
+impl<'a> Cell<'a> {
+    fn point(&self, point: Vector2<f32>) -> Vector2<f32> {
+        // Normalize to the tile, then flip in the order Tiled applies the flags.
+        let mut point = Vector2::new(point.x / self.tile_size.x, point.y / self.tile_size.y);
+        if self.flip.diagonal {
+            point = Vector2::new(point.y, point.x);
+        }
+        if self.flip.horizontal {
+            point.x = 1.0 - point.x;
+        }
+        if self.flip.vertical {
+            point.y = 1.0 - point.y;
+        }
+
+        let local = Point3::new(
+            self.origin.x + point.x * self.cell_size.x,
+            self.origin.y - point.y * self.cell_size.y,
+            0.0,
+        );
+        let world = self.global.0.transform_point(&local);
+        Vector2::new(world.x, world.y)
+    }
+
+    fn points(&self, points: &[Vector2<f32>]) -> Vec<Vector2<f32>> {
+        points.iter().map(|point| self.point(*point)).collect()
+    }
+
+    fn is_axis_aligned(&self) -> bool {
+        self.global.0[(0, 1)].abs() < std::f32::EPSILON
+            && self.global.0[(1, 0)].abs() < std::f32::EPSILON
+    }
+
+    fn to_world(&self, shape: &TileShape) -> CollisionShape {
+        match *shape {
+            TileShape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let corners = self.points(&[
+                    Vector2::new(x, y),
+                    Vector2::new(x + width, y),
+                    Vector2::new(x + width, y + height),
+                    Vector2::new(x, y + height),
+                ]);
+                if self.is_axis_aligned() {
+                    let (min, max) = bounds(corners.iter().cloned());
+                    CollisionShape::Rect { min, max }
+                } else {
+                    CollisionShape::Polygon(corners)
+                }
+            }
+            TileShape::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (min, max) =
+                    bounds(self.points(&[Vector2::new(x, y), Vector2::new(x + width, y + height)]));
+                CollisionShape::Ellipse {
+                    center: (min + max) / 2.0,
+                    radii: (max - min) / 2.0,
+                }
+            }
+            TileShape::Polygon(ref points) => CollisionShape::Polygon(self.points(points)),
+            TileShape::Polyline(ref points) => CollisionShape::Polyline(self.points(points)),
+            TileShape::Point(point) => CollisionShape::Point(self.point(point)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use amethyst::core::nalgebra::{Matrix4, Vector3};
+
+    use super::*;
+    use crate::tilemap::TileData;
+
+    /// Places shapes in the top left cell of a 2 x 2 map of 16 x 16 tiles, centred on `global`
+    fn place(shape: &TileShape, flip: TileFlip, global: &GlobalTransform) -> CollisionShape {
+        let dimensions = TilemapDimensions {
+            width: 2,
+            height: 2,
+            tile_width: 16,
+            tile_height: 16,
+        };
+        let cell = Cell {
+            origin: dimensions.tile_top_left(0, 0),
+            cell_size: Vector2::new(16.0, 16.0),
+            tile_size: Vector2::new(16.0, 16.0),
+            flip,
+            global,
+        };
+        cell.to_world(shape)
+    }
+
+    fn flips() -> [TileFlip; 4] {
+        [
+            TileFlip::default(),
+            TileFlip {
+                horizontal: true,
+                ..TileFlip::default()
+            },
+            TileFlip {
+                vertical: true,
+                ..TileFlip::default()
+            },
+            TileFlip {
+                diagonal: true,
+                ..TileFlip::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn rects_follow_flips() {
+        let rect = TileShape::Rect {
+            x: 0.0,
+            y: 8.0,
+            width: 4.0,
+            height: 4.0,
+        };
+        let expected = [
+            ((-16.0, 4.0), (-12.0, 8.0)),
+            ((-4.0, 4.0), (0.0, 8.0)),
+            ((-16.0, 8.0), (-12.0, 12.0)),
+            ((-8.0, 12.0), (-4.0, 16.0)),
+        ];
+        for (flip, &(min, max)) in flips().iter().zip(expected.iter()) {
+            assert_eq!(
+                place(&rect, *flip, &GlobalTransform::default()),
+                CollisionShape::Rect {
+                    min: Vector2::new(min.0, min.1),
+                    max: Vector2::new(max.0, max.1),
+                },
+                "{:?}",
+                flip
+            );
+        }
+    }
+
+    #[test]
+    fn polygons_follow_flips() {
+        let polygon = TileShape::Polygon(vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(8.0, 0.0),
+            Vector2::new(0.0, 4.0),
+        ]);
+        let expected = [
+            [(-16.0, 16.0), (-8.0, 16.0), (-16.0, 12.0)],
+            [(0.0, 16.0), (-8.0, 16.0), (0.0, 12.0)],
+            [(-16.0, 0.0), (-8.0, 0.0), (-16.0, 4.0)],
+            [(-16.0, 16.0), (-16.0, 8.0), (-12.0, 16.0)],
+        ];
+        for (flip, points) in flips().iter().zip(expected.iter()) {
+            let points = points.iter().map(|&(x, y)| Vector2::new(x, y)).collect();
+            assert_eq!(
+                place(&polygon, *flip, &GlobalTransform::default()),
+                CollisionShape::Polygon(points),
+                "{:?}",
+                flip
+            );
+        }
+    }
+
+    #[test]
+    fn shapes_follow_the_layer_transform() {
+        let global = GlobalTransform(Matrix4::new_translation(&Vector3::new(100.0, 50.0, 0.0)));
+        let rect = TileShape::Rect {
+            x: 0.0,
+            y: 8.0,
+            width: 4.0,
+            height: 4.0,
+        };
+        let flip = TileFlip {
+            horizontal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(
+            place(&rect, flip, &global),
+            CollisionShape::Rect {
+                min: Vector2::new(96.0, 54.0),
+                max: Vector2::new(100.0, 58.0),
+            }
+        );
+
+        // Rotated layers turn rects into polygons
+        let global = GlobalTransform(Matrix4::new_rotation(Vector3::new(
+            0.0,
+            0.0,
+            std::f32::consts::FRAC_PI_4,
+        )));
+        match place(&rect, TileFlip::default(), &global) {
+            CollisionShape::Polygon(points) => assert_eq!(points.len(), 4),
+            shape => panic!("{:?} isn't a polygon", shape),
+        }
+    }
+
+    #[test]
+    fn shapes_are_keyed_by_tileset_and_tile() {
+        let mut tileset = TilesetData {
+            name: "walls".to_owned(),
+            ..TilesetData::default()
+        };
+        let shape = TileShape::Point(Vector2::new(8.0, 8.0));
+        let mut tile = TileData::default();
+        tile.collision.push(shape.clone());
+        tileset.tiles.insert(3, tile);
+        tileset.tiles.insert(4, Default::default());
+
+        let mut collision = TileCollision::default();
+        collision.add_tileset(&tileset);
+        assert_eq!(collision.tile_shapes("walls", 3), &[shape][..]);
+        assert!(collision.tile_shapes("walls", 4).is_empty());
+        assert!(collision.tile_shapes("floors", 3).is_empty());
+    }
+}
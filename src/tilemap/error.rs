@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use tiled::{Orientation, TiledError};
+use xml::{reader, writer};
+
+/// Errors that can occur while loading a tilemap
+#[derive(Debug)]
+pub enum TilemapError {
+    /// The map file could not be read
+    Io(io::Error),
+    /// The map file is not a valid .tmx file
+    Parse(TiledError),
+    /// The map or one of its tilesets is not well-formed XML
+    Xml(reader::Error),
+    /// The map could not be written as a .tmx file
+    Write(writer::Error),
+    /// The map does not contain any tilesets
+    MissingTileset,
+    /// No tile layers were given to write a map from
+    NoLayers,
+    /// An external .tsx tileset referenced by the map could not be read
+    MissingExternalTileset { path: String, source: io::Error },
+    /// A tileset does not reference an image
+    MissingImage(String),
+    /// A layer has tiles from more tilesets than `DrawTilemap` can bind
+    TooManyTilesets {
+        layer: String,
+        count: usize,
+        max: usize,
+    },
+    /// An object prefab was spawned without a `PrefabLoaderSystem<AnimatedSpritePrefab>`
+    MissingPrefabLoader(String),
+    /// Only orthogonal maps can be rendered
+    UnsupportedOrientation(Orientation),
+}
+
+impl fmt::Display for TilemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TilemapError::Io(e) => write!(f, "Error opening .tmx file: {}", e),
+            TilemapError::Parse(e) => write!(f, "Error while parsing .tmx file: {}", e),
+            TilemapError::Xml(e) => write!(f, "Error while reading .tmx file: {}", e),
+            TilemapError::Write(e) => write!(f, "Error while writing .tmx file: {}", e),
+            TilemapError::MissingTileset => write!(f, "Tilemap has no tilesets"),
+            TilemapError::NoLayers => write!(f, "Tilemap has no layers to write"),
+            TilemapError::MissingExternalTileset { path, source } => {
+                write!(f, "External tileset {} could not be read: {}", path, source)
+            }
+            TilemapError::MissingImage(tileset) => write!(f, "Tileset {} has no image", tileset),
+            TilemapError::TooManyTilesets { layer, count, max } => write!(
+                f,
+                "Layer {} uses {} tilesets, at most {} are supported",
+                layer, count, max
+            ),
+            TilemapError::MissingPrefabLoader(prefab) => write!(
+                f,
+                "Object prefab {} needs a PrefabLoaderSystem<AnimatedSpritePrefab>",
+                prefab
+            ),
+            TilemapError::UnsupportedOrientation(orientation) => {
+                write!(f, "Unsupported tilemap orientation: {:?}", orientation)
+            }
+        }
+    }
+}
+
+impl Error for TilemapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TilemapError::Io(e) => Some(e),
+            TilemapError::Parse(e) => Some(e),
+            TilemapError::Xml(e) => Some(e),
+            TilemapError::Write(e) => Some(e),
+            TilemapError::MissingExternalTileset { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TilemapError {
+    fn from(e: io::Error) -> Self {
+        TilemapError::Io(e)
+    }
+}
+
+impl From<TiledError> for TilemapError {
+    fn from(e: TiledError) -> Self {
+        TilemapError::Parse(e)
+    }
+}
+
+impl From<reader::Error> for TilemapError {
+    fn from(e: reader::Error) -> Self {
+        TilemapError::Xml(e)
+    }
+}
+
+impl From<writer::Error> for TilemapError {
+    fn from(e: writer::Error) -> Self {
+        TilemapError::Write(e)
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use amethyst::assets::{AssetStorage, Handle, Prefab, PrefabLoader, RonFormat};
+use amethyst::ecs::Entity;
+use amethyst::prelude::*;
+
+use log::debug;
+
+use super::{Property, TiledObject, TilemapError};
+use crate::prefab::sprite::{AnimatedSpritePrefab, AnimatedSpritePrefabOverride};
+
+pub type AnimatedSpritePrefabHandle = Handle<Prefab<AnimatedSpritePrefab>>;
+
+/// Changes a field of the prefab of an object from the value of one of its properties
+pub type PrefabPropertyHook = Arc<dyn Fn(&mut AnimatedSpritePrefab, &Property) + Send + Sync>;
+
+/// What to do with an object of a registered type when its map is spawned
+#[derive(Clone)]
+pub enum ObjectFactory {
+    /// Instantiates the `AnimatedSpritePrefab` RON file at this path on the object's entity.
+    /// Requires a `PrefabLoaderSystem<AnimatedSpritePrefab>` to be running, spawning the map
+    /// fails without one.
+    Prefab(String),
+    /// Called with the object's entity once it has been created
+    Spawn(Arc<dyn Fn(&mut World, Entity, &TiledObject) + Send + Sync>),
+}
+
+/// Maps Tiled object types to the factory used to spawn them.
+/// Add it as a resource before spawning a map, objects without a registered type only get
+/// their `TiledObject` and `Transform`.
+/// Properties of objects spawned from a prefab can override fields of the prefab through
+/// `with_prefab_property`. The `sprite_number` int property sets the initial sprite.
+pub struct ObjectFactories {
+    factories: HashMap<String, ObjectFactory>,
+    prefab_handles: HashMap<String, AnimatedSpritePrefabHandle>,
+    prefab_properties: HashMap<String, PrefabPropertyHook>,
+}
+
+impl Default for ObjectFactories {
+    fn default() -> Self {
+        ObjectFactories {
+            factories: HashMap::new(),
+            prefab_handles: HashMap::new(),
+            prefab_properties: HashMap::new(),
+        }
+        .with_prefab_property("sprite_number", |prefab, property| {
+            if let Some(sprite_number) = property.as_int() {
+                prefab.sprite_number = sprite_number as usize;
+            }
+        })
+    }
+}
+
+impl ObjectFactories {
+    /// Instantiates the `AnimatedSpritePrefab` at `prefab_path` for objects of `object_type`
+    pub fn with_prefab(mut self, object_type: &str, prefab_path: &str) -> Self {
+        self.register(object_type, ObjectFactory::Prefab(prefab_path.to_owned()));
+        self
+    }
+
+    /// Calls `spawn` for objects of `object_type`
+    pub fn with_spawn<F>(mut self, object_type: &str, spawn: F) -> Self
+    where
+        F: Fn(&mut World, Entity, &TiledObject) + Send + Sync + 'static,
+    {
+        self.register(object_type, ObjectFactory::Spawn(Arc::new(spawn)));
+        self
+    }
+
+    /// Calls `hook` with the prefab of objects that have the property `property` and its value,
+    /// before the prefab is added to their entity
+    pub fn with_prefab_property<F>(mut self, property: &str, hook: F) -> Self
+    where
+        F: Fn(&mut AnimatedSpritePrefab, &Property) + Send + Sync + 'static,
+    {
+        self.prefab_properties
+            .insert(property.to_owned(), Arc::new(hook));
+        self
+    }
+
+    pub fn register(&mut self, object_type: &str, factory: ObjectFactory) {
+        self.factories.insert(object_type.to_owned(), factory);
+    }
+
+    pub fn get(&self, object_type: &str) -> Option<&ObjectFactory> {
+        self.factories.get(object_type)
+    }
+}
+
+/// Runs the factory registered for the type of `object`, if any, on its entity.
+/// Prefabs are loaded once per path and shared by every object using them.
+pub fn apply_object_factory(
+    world: &mut World,
+    entity: Entity,
+    object: &TiledObject,
+) -> Result<(), TilemapError> {
+    let factory = match world.res.try_fetch::<ObjectFactories>() {
+        Some(factories) => match factories.get(&object.object_type) {
+            Some(factory) => factory.clone(),
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    match factory {
+        ObjectFactory::Prefab(prefab_path) => {
+            // The prefab storages are only there once the loader system is set up
+            if !world
+                .res
+                .has_value::<AssetStorage<Prefab<AnimatedSpritePrefab>>>()
+            {
+                return Err(TilemapError::MissingPrefabLoader(prefab_path));
+            }
+            let cached = world
+                .read_resource::<ObjectFactories>()
+                .prefab_handles
+                .get(&prefab_path)
+                .cloned();
+            let handle = match cached {
+                Some(handle) => handle,
+                None => {
+                    debug!("Loading object prefab {}", prefab_path);
+                    let handle = world.exec(|loader: PrefabLoader<'_, AnimatedSpritePrefab>| {
+                        loader.load(prefab_path.as_str(), RonFormat, (), ())
+                    });
+                    world
+                        .write_resource::<ObjectFactories>()
+                        .prefab_handles
+                        .insert(prefab_path, handle.clone());
+                    handle
+                }
+            };
+            world
+                .write_storage::<AnimatedSpritePrefabHandle>()
+                .insert(entity, handle)
+                .expect("Object entity is alive");
+
+            let hooks = {
+                let factories = world.read_resource::<ObjectFactories>();
+                object
+                    .properties
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let hook = factories.prefab_properties.get(name)?;
+                        Some((Arc::clone(hook), value.clone()))
+                    })
+                    .collect::<Vec<_>>()
+            };
+            // Objects are already placed where the map puts them
+            let apply = move |prefab: &mut AnimatedSpritePrefab| {
+                prefab.keep_transform = true;
+                for (hook, value) in &hooks {
+                    hook(prefab, value);
+                }
+            };
+            world
+                .write_storage::<AnimatedSpritePrefabOverride>()
+                .insert(entity, AnimatedSpritePrefabOverride(Arc::new(apply)))
+                .expect("Object entity is alive");
+        }
+        ObjectFactory::Spawn(spawn) => spawn(world, entity, object),
+    }
+    Ok(())
+}
@@ -0,0 +1,387 @@
+use std::collections::BinaryHeap;
+
+use amethyst::core::nalgebra::Vector2;
+use amethyst::core::GlobalTransform;
+
+use super::pathfinding::{neighbours, Open};
+use super::{
+    CornerCutting, Neighbours, TileAnchor, TilemapDimensions, TilemapLayer, Tilesheets, Walkability,
+};
+
+/// Dijkstra map of a tile layer: the cost of the cheapest path from every cell to the closest
+/// of a set of source cells. Agents walk towards the sources by following the direction of
+/// decreasing distance, so one field serves any number of them.
+#[derive(Clone)]
+pub struct FlowField {
+    pub neighbours: Neighbours,
+    pub corner_cutting: CornerCutting,
+    pub walkability: Walkability,
+    width: u32,
+    height: u32,
+    sources: Vec<(u32, u32)>,
+    /// Cost of entering each cell in row order, `None` when it's blocked
+    costs: Vec<Option<f32>>,
+    /// Distance of each cell in row order, infinite when no source can be reached
+    distances: Vec<f32>,
+    /// `TilemapLayer::revision` the field was computed at
+    revision: u64,
+}
+
+impl FlowField {
+    pub fn new(
+        neighbours: Neighbours,
+        corner_cutting: CornerCutting,
+        walkability: Walkability,
+    ) -> Self {
+        FlowField {
+            neighbours,
+            corner_cutting,
+            walkability,
+            width: 0,
+            height: 0,
+            sources: Vec::new(),
+            costs: Vec::new(),
+            distances: Vec::new(),
+            revision: 0,
+        }
+    }
+
+    /// Computes the whole field again with new source cells
+    pub fn set_sources(
+        &mut self,
+        layer: &TilemapLayer,
+        tilesheets: &Tilesheets,
+        sources: &[(u32, u32)],
+    ) {
+        self.width = layer.width;
+        self.height = layer.height;
+        self.sources = sources.to_vec();
+        self.costs = self.layer_costs(layer, tilesheets);
+        self.distances = vec![std::f32::INFINITY; self.costs.len()];
+        self.revision = layer.revision();
+
+        let mut open = BinaryHeap::new();
+        for &(x, y) in sources {
+            if let Some(index) = self.index(x, y) {
+                if self.costs[index].is_some() {
+                    self.distances[index] = 0.0;
+                    open.push(Open {
+                        estimate: 0.0,
+                        cell: (x, y),
+                    });
+                }
+            }
+        }
+        self.propagate(open);
+    }
+
+    /// Brings the field up to date after tiles of the layer changed. Only the distances that
+    /// depended on the changed cells are computed again.
+    /// Returns whether anything had to be done.
+    pub fn update(&mut self, layer: &TilemapLayer, tilesheets: &Tilesheets) -> bool {
+        if layer.revision() == self.revision {
+            return false;
+        }
+        if layer.width != self.width || layer.height != self.height {
+            let sources = self.sources.clone();
+            self.set_sources(layer, tilesheets, &sources);
+            return true;
+        }
+
+        let costs = self.layer_costs(layer, tilesheets);
+        let changed = (0..costs.len())
+            .filter(|&index| costs[index] != self.costs[index])
+            .map(|index| self.cell(index))
+            .collect::<Vec<_>>();
+        self.revision = layer.revision();
+        if changed.is_empty() {
+            return false;
+        }
+
+        // Moves out of a cell depend on the cells around it when corners can't be cut, so
+        // those count as changed too.
+        let mut invalid = Vec::new();
+        let mut stack = Vec::new();
+        for &(x, y) in &changed {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (nx, ny) = (i64::from(x) + dx, i64::from(y) + dy);
+                    if nx >= 0
+                        && ny >= 0
+                        && nx < i64::from(self.width)
+                        && ny < i64::from(self.height)
+                    {
+                        stack.push((nx as u32, ny as u32));
+                    }
+                }
+            }
+        }
+
+        // Forget the distances of the changed cells and of every cell whose distance went
+        // through one of them, using the old costs.
+        let mut is_invalid = vec![false; self.distances.len()];
+        while let Some(cell) = stack.pop() {
+            let index = self.index(cell.0, cell.1).expect("Cell inside the field");
+            if is_invalid[index] {
+                continue;
+            }
+            is_invalid[index] = true;
+            invalid.push(cell);
+
+            let distance = self.distances[index];
+            self.distances[index] = std::f32::INFINITY;
+            if !distance.is_finite() {
+                continue;
+            }
+            let old_costs = &self.costs;
+            let width = self.width;
+            let walkable = |x: u32, y: u32| old_costs[(y * width + x) as usize].is_some();
+            for (next, step) in neighbours(
+                self.neighbours,
+                self.corner_cutting,
+                cell,
+                (self.width, self.height),
+                walkable,
+            ) {
+                let next_index = (next.1 * width + next.0) as usize;
+                let through = distance + step * old_costs[next_index].unwrap_or(1.0);
+                if !is_invalid[next_index] && self.distances[next_index] >= through - 1e-4 {
+                    stack.push(next);
+                }
+            }
+        }
+
+        // Fill the forgotten cells again from the valid cells around them.
+        self.costs = costs;
+        let mut open = BinaryHeap::new();
+        for &(x, y) in &invalid {
+            let index = self.index(x, y).expect("Cell inside the field");
+            let cost = match self.costs[index] {
+                Some(cost) => cost,
+                None => continue,
+            };
+            let mut distance = if self.sources.contains(&(x, y)) {
+                0.0
+            } else {
+                std::f32::INFINITY
+            };
+            let costs = &self.costs;
+            let width = self.width;
+            let walkable = |x: u32, y: u32| costs[(y * width + x) as usize].is_some();
+            for (next, step) in neighbours(
+                self.neighbours,
+                self.corner_cutting,
+                (x, y),
+                (self.width, self.height),
+                walkable,
+            ) {
+                let next_index = (next.1 * width + next.0) as usize;
+                distance = distance.min(self.distances[next_index] + step * cost);
+            }
+            if distance.is_finite() {
+                self.distances[index] = distance;
+                open.push(Open {
+                    estimate: distance,
+                    cell: (x, y),
+                });
+            }
+        }
+        self.propagate(open);
+        true
+    }
+
+    /// Distance from a cell to the closest source, `None` if it can't reach any
+    pub fn distance(&self, x: u32, y: u32) -> Option<f32> {
+        self.index(x, y)
+            .map(|index| self.distances[index])
+            .filter(|distance| distance.is_finite())
+    }
+
+    /// The neighbour of a cell closest to a source, `None` for sources and cells that can't
+    /// reach any
+    pub fn next_cell(&self, x: u32, y: u32) -> Option<(u32, u32)> {
+        let distance = self.distance(x, y)?;
+        let costs = &self.costs;
+        let width = self.width;
+        let walkable = |x: u32, y: u32| costs[(y * width + x) as usize].is_some();
+        neighbours(
+            self.neighbours,
+            self.corner_cutting,
+            (x, y),
+            (self.width, self.height),
+            walkable,
+        )
+        .map(|(next, _)| (next, self.distances[(next.1 * width + next.0) as usize]))
+        .filter(|(_, next_distance)| *next_distance < distance)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(next, _)| next)
+    }
+
+    /// Unit vector pointing from a world position towards the centre of the next cell on the
+    /// way to the closest source. `global` is the `GlobalTransform` of the layer.
+    /// `None` outside the layer, on sources and on cells that can't reach any.
+    pub fn steer(
+        &self,
+        dimensions: &TilemapDimensions,
+        global: &GlobalTransform,
+        position: Vector2<f32>,
+    ) -> Option<Vector2<f32>> {
+        let (x, y) = dimensions.world_to_tile(global, position)?;
+        let (next_x, next_y) = self.next_cell(x, y)?;
+        let target = dimensions.tile_to_world(global, next_x, next_y, TileAnchor::Center);
+        (target - position).try_normalize(std::f32::EPSILON)
+    }
+
+    fn layer_costs(&self, layer: &TilemapLayer, tilesheets: &Tilesheets) -> Vec<Option<f32>> {
+        let mut costs = Vec::with_capacity((layer.width * layer.height) as usize);
+        for y in 0..layer.height {
+            for x in 0..layer.width {
+                costs.push(self.walkability.cost(layer, tilesheets, x, y));
+            }
+        }
+        costs
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((y * self.width + x) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn cell(&self, index: usize) -> (u32, u32) {
+        (index as u32 % self.width, index as u32 / self.width)
+    }
+
+    /// Dijkstra from the open cells, whose distances are already set
+    fn propagate(&mut self, mut open: BinaryHeap<Open>) {
+        let width = self.width;
+        while let Some(Open { estimate, cell }) = open.pop() {
+            let index = (cell.1 * width + cell.0) as usize;
+            if estimate > self.distances[index] {
+                continue;
+            }
+
+            let costs = &self.costs;
+            let walkable = |x: u32, y: u32| costs[(y * width + x) as usize].is_some();
+            for (next, step) in neighbours(
+                self.neighbours,
+                self.corner_cutting,
+                cell,
+                (self.width, self.height),
+                walkable,
+            ) {
+                let next_index = (next.1 * width + next.0) as usize;
+                let distance = estimate + step * costs[next_index].unwrap_or(1.0);
+                if distance < self.distances[next_index] {
+                    self.distances[next_index] = distance;
+                    open.push(Open {
+                        estimate: distance,
+                        cell: next,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Properties, TileRegion};
+    use super::*;
+
+    const WALL: u32 = 1;
+
+    fn layer() -> TilemapLayer {
+        let (width, height) = (12, 9);
+        let mut layer = TilemapLayer::new(
+            "ground",
+            width,
+            height,
+            vec![0; (width * height) as usize],
+            Vec::new(),
+            4,
+            Properties::new(),
+        );
+        layer.fill_rect(TileRegion::new(3, 1, 1, 6), WALL);
+        layer.fill_rect(TileRegion::new(6, 3, 4, 1), WALL);
+        layer.set_tile(8, 6, WALL);
+        layer
+    }
+
+    fn assert_same_distances(field: &FlowField, layer: &TilemapLayer, sources: &[(u32, u32)]) {
+        let mut expected = field.clone();
+        expected.set_sources(layer, &Tilesheets::default(), sources);
+        for y in 0..layer.height {
+            for x in 0..layer.width {
+                match (field.distance(x, y), expected.distance(x, y)) {
+                    (Some(distance), Some(expected)) => assert!(
+                        (distance - expected).abs() < 1e-3,
+                        "({}, {}): {} instead of {}",
+                        x,
+                        y,
+                        distance,
+                        expected
+                    ),
+                    (distance, expected) => assert_eq!(distance, expected, "({}, {})", x, y),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn updates_match_full_recomputation() {
+        let sources = [(0, 0), (11, 8)];
+        let settings = [
+            (Neighbours::Four, CornerCutting::Never),
+            (Neighbours::Eight, CornerCutting::Never),
+            (Neighbours::Eight, CornerCutting::OneBlocked),
+            (Neighbours::Eight, CornerCutting::Always),
+        ];
+        for &(neighbours, corner_cutting) in &settings {
+            let tilesheets = Tilesheets::default();
+            let mut layer = layer();
+            let mut field = FlowField::new(neighbours, corner_cutting, Walkability::Blocking);
+            field.set_sources(&layer, &tilesheets, &sources);
+            assert!(!field.update(&layer, &tilesheets));
+
+            // Walls appear and disappear all over the layer
+            let mut seed = 7u32;
+            for _ in 0..40 {
+                for _ in 0..3 {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    let (x, y) = ((seed >> 8) % layer.width, (seed >> 16) % layer.height);
+                    let gid = if layer.get_tile(x, y) == Some(WALL) {
+                        0
+                    } else {
+                        WALL
+                    };
+                    layer.set_tile(x, y, gid);
+                }
+                field.update(&layer, &tilesheets);
+                assert_same_distances(&field, &layer, &sources);
+            }
+
+            // A source is walled in or freed, then put back
+            for _ in 0..2 {
+                let gid = if layer.get_tile(0, 0) == Some(WALL) {
+                    0
+                } else {
+                    WALL
+                };
+                layer.set_tile(0, 0, gid);
+                assert!(field.update(&layer, &tilesheets));
+                assert_same_distances(&field, &layer, &sources);
+            }
+
+            layer.fill_rect(TileRegion::new(0, 4, 12, 1), WALL);
+            assert!(field.update(&layer, &tilesheets));
+            assert_same_distances(&field, &layer, &sources);
+            layer.fill_rect(TileRegion::new(0, 4, 12, 1), 0);
+            assert!(field.update(&layer, &tilesheets));
+            assert_same_distances(&field, &layer, &sources);
+            assert!(!field.update(&layer, &tilesheets));
+        }
+    }
+}
@@ -0,0 +1,57 @@
+/// Bit Tiled sets on a gid when the tile is flipped horizontally
+pub const FLIPPED_HORIZONTALLY_FLAG: u32 = 0x8000_0000;
+/// Bit Tiled sets on a gid when the tile is flipped vertically
+pub const FLIPPED_VERTICALLY_FLAG: u32 = 0x4000_0000;
+/// Bit Tiled sets on a gid when the tile is flipped along its top left to bottom right diagonal
+pub const FLIPPED_DIAGONALLY_FLAG: u32 = 0x2000_0000;
+/// Bits of a gid that identify the tile itself
+pub const GID_MASK: u32 =
+    !(FLIPPED_HORIZONTALLY_FLAG | FLIPPED_VERTICALLY_FLAG | FLIPPED_DIAGONALLY_FLAG);
+
+/// How a tile is mirrored. Tiled applies the diagonal flip first, then the horizontal and
+/// vertical ones, so a rotation by 90 degrees clockwise is a diagonal plus horizontal flip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TileFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+    pub diagonal: bool,
+}
+
+impl TileFlip {
+    /// Flags as stored in the w channel of `TilemapLayer::tiles`, matching `tilemap_f.glsl`
+    pub fn shader_bits(self) -> u32 {
+        (self.horizontal as u32) | ((self.vertical as u32) << 1) | ((self.diagonal as u32) << 2)
+    }
+
+    /// Flags as stored in the high bits of a gid
+    pub fn gid_bits(self) -> u32 {
+        let mut bits = 0;
+        if self.horizontal {
+            bits |= FLIPPED_HORIZONTALLY_FLAG;
+        }
+        if self.vertical {
+            bits |= FLIPPED_VERTICALLY_FLAG;
+        }
+        if self.diagonal {
+            bits |= FLIPPED_DIAGONALLY_FLAG;
+        }
+        bits
+    }
+}
+
+/// Splits a raw gid as found in a .tmx layer into the tile gid and its flip flags.
+pub fn decode_gid(raw_gid: u32) -> (u32, TileFlip) {
+    (
+        raw_gid & GID_MASK,
+        TileFlip {
+            horizontal: raw_gid & FLIPPED_HORIZONTALLY_FLAG != 0,
+            vertical: raw_gid & FLIPPED_VERTICALLY_FLAG != 0,
+            diagonal: raw_gid & FLIPPED_DIAGONALLY_FLAG != 0,
+        },
+    )
+}
+
+/// Combines a tile gid and its flip flags into a raw gid as stored in a .tmx layer.
+pub fn encode_gid(gid: u32, flip: TileFlip) -> u32 {
+    (gid & GID_MASK) | flip.gid_bits()
+}
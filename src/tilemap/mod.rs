@@ -1,140 +1,379 @@
 use amethyst::assets::Loader;
-use amethyst::core::nalgebra::{Vector2, Vector3};
+use amethyst::core::nalgebra::{Point3, Vector2, Vector3};
+use amethyst::core::transform::Parent;
 use amethyst::core::{GlobalTransform, Transform};
-use amethyst::ecs::{Component, DenseVecStorage};
+use amethyst::ecs::{Component, DenseVecStorage, Entity};
 use amethyst::prelude::*;
 use amethyst::renderer::PosTex;
-use amethyst::renderer::{Mesh, PngFormat, TextureMetadata};
+use amethyst::renderer::{Mesh, PngFormat, TextureHandle, TextureMetadata};
 use genmesh::generators::{IndexedPolygon, Plane, SharedVertex};
 use genmesh::{Triangulate, Vertices};
 
-use std::fs::File;
+use std::fs;
 use std::path::{Path, PathBuf};
-use tiled::parse;
+use std::sync::Arc;
+use tiled::Orientation;
 
-use log::{debug, error};
+use log::{debug, warn};
 
-pub use self::tilemap_pass::DrawTilemap;
+use self::tsx::normalize;
 
+pub use self::animation::{current_frame, AnimatedTiles, AnimationFrame, TileAnimationSystem};
+pub use self::asset::{
+    Tilemap, TilemapBundle, TilemapHandle, TilemapLayerFilter, TilemapLayers, TilemapSpawnSystem,
+    TmxFormat,
+};
+pub use self::autotile::{
+    AutotileRule, AutotileSystem, Autotiler, TerrainGrid, WangTile, BLOB_MASKS,
+};
+pub use self::builder::TilemapBuilder;
+pub use self::collision::{CollisionShape, TileCollision, TileShape};
+pub use self::error::TilemapError;
+pub use self::factory::{
+    apply_object_factory, AnimatedSpritePrefabHandle, ObjectFactories, ObjectFactory,
+    PrefabPropertyHook,
+};
+pub use self::flowfield::FlowField;
+pub use self::gid::{decode_gid, encode_gid, TileFlip};
+pub use self::object::{
+    object_transform, spawn_objects, ObjectAttributes, ObjectGroupAttributes, ObjectLayer,
+    ObjectShape, TiledObject,
+};
+pub use self::pathfinding::{CornerCutting, Neighbours, Pathfinder, Walkability};
+pub use self::picking::{pick_tiles, screen_ray, HoveredTile, TileHit, TilePickSystem};
+pub use self::property::{convert_properties, MapProperties, Properties, Property};
+pub use self::terrain::{Side, Terrain, TileTerrain, WangColor, WangSet, WangSetType};
+pub use self::tilemap_pass::{DrawTilemap, CHUNK_SIZE, MAX_TILESHEETS};
+pub use self::tileset::{TileData, TilesetData};
+pub use self::tmx::{write_tmx, LayerEncoding};
+pub use self::tsx::{parse_map, TiledMap, TsxCache};
+pub use self::visibility::{
+    cast_ray, field_of_view, layer_opacity, line, line_of_sight, Fog, FogOfWar, RayHit,
+    VisibleTiles,
+};
+
+mod animation;
+mod asset;
+mod autotile;
+mod builder;
+mod collision;
+mod error;
+mod factory;
+mod flowfield;
+pub mod gid;
+mod object;
+mod pathfinding;
+mod picking;
+pub mod procgen;
+mod property;
+mod terrain;
 mod tilemap_pass;
+mod tileset;
+mod tmx;
+mod tsx;
+mod visibility;
 
-pub fn initialise_tilemap(world: &mut World, base_dir: &str, map_name: &str) {
+/// Loads a .tmx map and creates an entity for each of its tile layers.
+/// External tilesets are cached in the `TsxCache` resource, which is added if missing.
+/// Returns the created layer entities.
+pub fn initialise_tilemap(
+    world: &mut World,
+    base_dir: &str,
+    map_name: &str,
+) -> Result<Vec<Entity>, TilemapError> {
     let mut path_buf = PathBuf::new();
     path_buf.push(base_dir);
     path_buf.push(map_name);
     debug!("Loading tilemap {}", path_buf.to_str().unwrap());
-    use amethyst::assets::Handle;
-    use amethyst::renderer::{Material, MaterialDefaults};
 
     let map_path = path_buf.as_path();
-    let map_file = match File::open(map_path) {
-        Err(e) => {
-            error!("Error opening .tmx file: {}", e);
-            return;
-        }
-        Ok(f) => f,
-    };
-    let map = match parse(map_file) {
-        Err(e) => {
-            error!("Error while parsing .tmx file: {}", e);
-            return;
-        }
-        Ok(m) => m,
-    };
-    let (tileset, tileset_img) = match map.tilesets.get(0) {
-        Some(tileset) => match tileset.images.get(0) {
-            Some(img) => (tileset, img),
-            None => return,
-        },
-        None => return,
-    };
-    let tileset_width = tileset_img.width as u32 / tileset.tile_width;
-    let tileset_height = tileset_img.height as u32 / tileset.tile_height;
-    let image_source = &tileset_img.source;
+    let bytes = fs::read(map_path)?;
+
+    if !world.res.has_value::<TsxCache>() {
+        world.add_resource(TsxCache::default());
+    }
+    let tsx_cache = TsxCache::clone(&world.read_resource::<TsxCache>());
+    let map = parse_map(&bytes, map_name, &tsx_cache, |path| {
+        fs::read(Path::new(base_dir).join(path)).map_err(|source| {
+            TilemapError::MissingExternalTileset {
+                path: path.to_owned(),
+                source,
+            }
+        })
+    })?;
+    spawn_tilemap(
+        world,
+        &map,
+        map_path.parent().unwrap_or(Path::new("")),
+        None,
+        None,
+    )
+}
+
+/// Creates an entity for each tile layer and each object of an already parsed map.
+/// The collision shapes of its tilesets are added to the `TileCollision` resource.
+/// Tilesheet images are loaded relative to `map_dir`. When a `parent` is given, the entities
+/// are attached to it and follow its transform. When `layer_names` is given, only the tile and
+/// object layers it names are spawned. Each tile layer can have tiles from at most
+/// `MAX_TILESHEETS` tilesets.
+pub fn spawn_tilemap(
+    world: &mut World,
+    tiled_map: &TiledMap,
+    map_dir: &Path,
+    parent: Option<Entity>,
+    layer_names: Option<&[String]>,
+) -> Result<Vec<Entity>, TilemapError> {
+    let map = &tiled_map.map;
+    if map.orientation != Orientation::Orthogonal {
+        return Err(TilemapError::UnsupportedOrientation(map.orientation));
+    }
+    if map.tilesets.is_empty() {
+        return Err(TilemapError::MissingTileset);
+    }
 
+    let chunk_size = chunk_size(world);
     let tilemap_dimensions = TilemapDimensions {
         width: map.width,
         height: map.height,
+        tile_width: map.tile_width,
+        tile_height: map.tile_height,
     };
 
-    let tilesheet_dimensions = TilesheetDimensions {
-        width: tileset_width,
-        height: tileset_height,
-    };
+    let mut tilesheets = Tilesheets::default();
+    let sources = tiled_map
+        .tileset_sources
+        .iter()
+        .cloned()
+        .chain(std::iter::repeat(None));
+    for ((tileset, data), source) in map.tilesets.iter().zip(&tiled_map.tilesets).zip(sources) {
+        let tileset_img = tileset
+            .images
+            .get(0)
+            .ok_or_else(|| TilemapError::MissingImage(tileset.name.clone()))?;
 
-    let layers = &map.layers;
-    for layer in layers {
-        let tilemap_layer = TilemapLayer {
-            name: String::from(layer.name.as_str()),
-            tiles: generate_tile_data(&layer, tileset_width, tileset_height),
+        let (tileset_path_buf, image_source) = image_paths(map_dir, &tileset_img.source);
+
+        let texture = {
+            let loader = world.read_resource::<Loader>();
+            loader.load(
+                tileset_path_buf.to_str().unwrap(),
+                PngFormat,
+                TextureMetadata::srgb_scale(),
+                (),
+                &world.read_resource(),
+            )
         };
 
-        let half_width: f32 = ((map.width * map.tile_width) / 2) as f32;
-        let half_height: f32 = ((map.height * map.tile_height) / 2) as f32;
+        tilesheets.sheets.push(Tilesheet {
+            dimensions: TilesheetDimensions::from_tileset(tileset, tileset_img),
+            image_source,
+            source,
+            texture,
+            data: Arc::clone(data),
+        });
+    }
+    // Tiled writes tilesets in gid order, but gid lookups rely on it so make sure.
+    tilesheets
+        .sheets
+        .sort_by_key(|sheet| sheet.dimensions.first_gid);
 
-        let (mesh, material) = {
-            let loader = world.read_resource::<Loader>();
+    add_collision(world, &tilesheets);
+
+    let tilesheet_dimensions = tilesheets.dimensions();
+    let map_properties = MapProperties(convert_properties(&map.properties));
+    if let Some(parent) = parent {
+        world
+            .write_storage::<MapProperties>()
+            .insert(parent, map_properties.clone())
+            .expect("Tilemap parent is alive");
+    }
 
-            let mesh: Handle<Mesh> = loader.load_from_data(
-                generate_tilemap_plane(map.tile_width, map.width, map.height).into(),
+    let mut entities = Vec::with_capacity(map.layers.len());
+    let layers = &map.layers;
+    for (map_index, layer) in layers.iter().enumerate() {
+        if let Some(layer_names) = layer_names {
+            if !layer_names.iter().any(|name| *name == layer.name) {
+                continue;
+            }
+        }
+
+        let mut tilemap_layer = TilemapLayer::new(
+            &layer.name,
+            map.width,
+            map.height,
+            layer.tiles.iter().flatten().cloned().collect(),
+            tilesheet_dimensions.clone(),
+            chunk_size,
+            convert_properties(&layer.properties),
+        );
+        tilemap_layer.map_index = tiled_map
+            .layer_indices
+            .get(map_index)
+            .cloned()
+            .unwrap_or(map_index);
+        tilemap_layer.opacity = layer.opacity;
+        tilemap_layer.visible = layer.visible;
+        let layer_entity = spawn_layer(
+            world,
+            tilemap_layer,
+            &tilemap_dimensions,
+            &tilesheets,
+            &map_properties,
+            parent,
+        )?;
+        entities.push(layer_entity);
+    }
+
+    entities.extend(spawn_objects(world, tiled_map, parent, layer_names)?);
+
+    Ok(entities)
+}
+
+/// Path a tilesheet image is loaded from, given the directory of its map and the path written
+/// in the map, and the path kept in `Tilesheet::image_source`.
+/// `map_dir` is relative to the asset directory for maps loaded as assets, but
+/// `initialise_tilemap` joins it to its base directory, so it can be absolute.
+pub(crate) fn image_paths(map_dir: &Path, source: &str) -> (PathBuf, String) {
+    (map_dir.join(source), normalize(Path::new(source)))
+}
+
+/// Chunk size set in the `TilemapSettings` resource, `CHUNK_SIZE` without one
+fn chunk_size(world: &World) -> u32 {
+    world
+        .res
+        .try_fetch::<TilemapSettings>()
+        .map(|settings| settings.chunk_size)
+        .unwrap_or(CHUNK_SIZE)
+        .max(1)
+}
+
+/// Adds the collision shapes of tilesheets to the `TileCollision` resource, adding it if missing
+fn add_collision(world: &mut World, tilesheets: &Tilesheets) {
+    if !world.res.has_value::<TileCollision>() {
+        world.add_resource(TileCollision::default());
+    }
+    let mut collision = world.write_resource::<TileCollision>();
+    for sheet in &tilesheets.sheets {
+        collision.add_tileset(&sheet.data);
+    }
+}
+
+/// Creates the entity of a tile layer, centred on its parent, and the entities of its chunks
+fn spawn_layer(
+    world: &mut World,
+    tilemap_layer: TilemapLayer,
+    tilemap_dimensions: &TilemapDimensions,
+    tilesheets: &Tilesheets,
+    map_properties: &MapProperties,
+    parent: Option<Entity>,
+) -> Result<Entity, TilemapError> {
+    use amethyst::assets::Handle;
+
+    let chunk_size = tilemap_layer.chunk_size;
+    if tilemap_layer.sheet_slots.len() > MAX_TILESHEETS {
+        return Err(TilemapError::TooManyTilesets {
+            layer: tilemap_layer.name.clone(),
+            count: tilemap_layer.sheet_slots.len(),
+            max: MAX_TILESHEETS,
+        });
+    }
+
+    let (width, height) = (tilemap_layer.width, tilemap_layer.height);
+    let (tile_width, tile_height) = (
+        tilemap_dimensions.tile_width,
+        tilemap_dimensions.tile_height,
+    );
+    let half_size = tilemap_dimensions.half_size();
+    let (half_width, half_height) = (half_size.x, half_size.y);
+
+    let mut transform = Transform::default();
+    transform.set_x(half_width);
+    transform.set_y(half_height);
+    transform.set_z(0.0);
+
+    let animated_tiles = AnimatedTiles::from_layer(&tilemap_layer, tilesheets);
+    let mut builder = world
+        .create_entity()
+        .with(transform)
+        .with(GlobalTransform::default())
+        .with(tilemap_dimensions.clone())
+        .with(tilesheets.clone())
+        .with(map_properties.clone())
+        .with(tilemap_layer)
+        .with(animated_tiles);
+    if let Some(parent) = parent {
+        builder = builder.with(Parent { entity: parent });
+    }
+    let layer_entity = builder.build();
+
+    // Each chunk gets its own mesh, positioned relative to the centre of the layer.
+    for chunk in generate_chunks(layer_entity, width, height, chunk_size) {
+        let mesh: Handle<Mesh> = {
+            let loader = world.read_resource::<Loader>();
+            loader.load_from_data(
+                generate_tilemap_plane(tile_width, tile_height, chunk.width, chunk.height).into(),
                 (),
                 &world.read_resource(),
-            );
-
-            let mat_defaults = world.read_resource::<MaterialDefaults>();
-
-            let tex_storage = world.read_resource();
-
-            let mut tileset_path_buf = PathBuf::new();
-            tileset_path_buf.push(map_path.parent().unwrap_or(Path::new("")).as_os_str());
-            tileset_path_buf.push(image_source);
-            let tilemap_material = Material {
-                albedo: loader.load(
-                    tileset_path_buf.to_str().unwrap(),
-                    PngFormat,
-                    TextureMetadata::srgb_scale(),
-                    (),
-                    &tex_storage,
-                ),
-                ..mat_defaults.0.clone()
-            };
-
-            (mesh, tilemap_material)
+            )
         };
 
         let mut transform = Transform::default();
-        transform.set_x(half_width);
-        transform.set_y(half_height);
-        transform.set_z(0.0);
+        transform.set_x(((chunk.x * 2 + chunk.width) * tile_width) as f32 / 2.0 - half_width);
+        transform.set_y(half_height - ((chunk.y * 2 + chunk.height) * tile_height) as f32 / 2.0);
 
         world
             .create_entity()
             .with(mesh)
-            .with(material)
             .with(transform)
             .with(GlobalTransform::default())
-            .with(tilemap_dimensions.clone())
-            .with(tilesheet_dimensions.clone())
-            .with(tilemap_layer)
+            .with(Parent {
+                entity: layer_entity,
+            })
+            .with(chunk)
             .build();
     }
+
+    Ok(layer_entity)
+}
+
+/// Splits a layer into chunks of at most `chunk_size` x `chunk_size` tiles.
+pub fn generate_chunks(
+    layer: Entity,
+    tilemap_width: u32,
+    tilemap_height: u32,
+    chunk_size: u32,
+) -> Vec<TilemapChunk> {
+    let mut chunks = Vec::new();
+    for y in (0..tilemap_height).step_by(chunk_size as usize) {
+        for x in (0..tilemap_width).step_by(chunk_size as usize) {
+            chunks.push(TilemapChunk {
+                layer,
+                x,
+                y,
+                width: chunk_size.min(tilemap_width - x),
+                height: chunk_size.min(tilemap_height - y),
+            });
+        }
+    }
+    chunks
 }
 
 pub fn generate_tilemap_plane(
-    tilesize: u32,
+    tile_width: u32,
+    tile_height: u32,
     tilemap_width: u32,
     tilemap_height: u32,
 ) -> Vec<PosTex> {
     let plane = Plane::new();
 
-    let half_width = (tilesize * tilemap_width) as f32 / 2.0;
-    let half_height = (tilesize * tilemap_height) as f32 / 2.0;
+    let half_width = (tile_width * tilemap_width) as f32 / 2.0;
+    let half_height = (tile_height * tilemap_height) as f32 / 2.0;
 
     let vertex_data: Vec<PosTex> = plane
         .shared_vertex_iter()
         .map(|(raw_x, raw_y)| {
-            let vertex_x = (half_width * raw_x).round();
-            let vertex_y = (half_height * raw_y).round();
+            let vertex_x = half_width * raw_x;
+            let vertex_y = half_height * raw_y;
 
             let u_pos = (1.0 + raw_x) / 2.0;
             let v_pos = (1.0 + raw_y) / 2.0;
@@ -164,58 +403,773 @@ pub fn generate_tilemap_plane(
     indexed_vertex_data
 }
 
-pub fn generate_tile_data(
-    layer: &tiled::Layer,
-    tileset_width: u32,
-    tileset_height: u32,
-) -> Vec<[f32; 4]> {
-    let mut tiles = Vec::new();
-    for rows in &layer.tiles {
-        for tile in rows {
-            if *tile != 0 {
-                // subtract 1.0 from the x coordinate because the first gid of the tileset is 1
-                // this could be made cleaner
-                tiles.push([
-                    (*tile - 1) as f32 % tileset_width as f32,
-                    (tileset_height - 1) as f32 - (((*tile - 1) / tileset_width) as f32),
-                    0.0,
-                    0.0,
-                ]);
-            } else {
-                // There's no tile, so push negative tile coords so the shader can discard.
-                tiles.push([-1.0, -1.0, 0.0, 0.0]);
-            }
+/// Finds the tilesheet a gid belongs to, returning its index in `tilesheets` along with it.
+/// `tilesheets` must be sorted by `first_gid`.
+pub fn tilesheet_for_gid(
+    tilesheets: &[TilesheetDimensions],
+    gid: u32,
+) -> Option<(usize, &TilesheetDimensions)> {
+    tilesheets
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, sheet)| sheet.first_gid <= gid)
+        .filter(|(_, sheet)| gid - sheet.first_gid < sheet.tile_count())
+}
+
+/// Converts a raw gid, flip flags included, into the shader entry of a tile.
+/// The x and y channels hold the column and row, counted from the top, of the tile in its
+/// tilesheet, z the index of the tilesheet and w the flip flags (see `TileFlip::shader_bits`).
+pub fn tile_entry(tilesheets: &[TilesheetDimensions], raw_gid: u32) -> [f32; 4] {
+    let (gid, flip) = decode_gid(raw_gid);
+    match tilesheet_for_gid(tilesheets, gid) {
+        Some((sheet_index, sheet)) => {
+            // gids are offset by the first gid of the tileset they come from
+            let local_id = gid - sheet.first_gid;
+            [
+                (local_id % sheet.width) as f32,
+                (local_id / sheet.width) as f32,
+                sheet_index as f32,
+                flip.shader_bits() as f32,
+            ]
         }
+        // There's no tile, so use negative tile coords so the shader can discard.
+        None => [-1.0, -1.0, 0.0, 0.0],
     }
-    tiles
+}
+
+/// `tile_entry` with the z channel indexing into `slots`, the indices in `tilesheets` of the
+/// tilesheets bound when drawing a layer. Tiles from a tilesheet without a slot are empty.
+fn slot_entry(tilesheets: &[TilesheetDimensions], slots: &[usize], raw_gid: u32) -> [f32; 4] {
+    let (gid, _) = decode_gid(raw_gid);
+    let slot = tilesheet_for_gid(tilesheets, gid).and_then(|(sheet_index, _)| {
+        slots
+            .iter()
+            .take(MAX_TILESHEETS)
+            .position(|slot| *slot == sheet_index)
+    });
+    match slot {
+        Some(slot) => {
+            let mut entry = tile_entry(tilesheets, raw_gid);
+            entry[2] = slot as f32;
+            entry
+        }
+        None => [-1.0, -1.0, 0.0, 0.0],
+    }
+}
+
+/// Indices in `tilesheets` of the tilesheets the tiles of a layer come from, in gid order
+fn used_tilesheets(tilesheets: &[TilesheetDimensions], gids: &[u32]) -> Vec<usize> {
+    let mut used = gids
+        .iter()
+        .filter_map(|gid| tilesheet_for_gid(tilesheets, decode_gid(*gid).0))
+        .map(|(sheet_index, _)| sheet_index)
+        .collect::<Vec<_>>();
+    used.sort();
+    used.dedup();
+    used
 }
 
 #[derive(Clone)]
 pub struct TilemapDimensions {
     pub width: u32,
     pub height: u32,
+    /// Width of a tile in pixels
+    pub tile_width: u32,
+    /// Height of a tile in pixels
+    pub tile_height: u32,
+}
+
+impl TilemapDimensions {
+    /// Offset from the bottom left corner of a layer to its centre, where its `Transform` is
+    pub fn half_size(&self) -> Vector2<f32> {
+        Vector2::new(
+            (self.width * self.tile_width) as f32 / 2.0,
+            (self.height * self.tile_height) as f32 / 2.0,
+        )
+    }
+
+    /// Position of the top left corner of a tile, relative to the centre of its layer
+    pub fn tile_top_left(&self, x: u32, y: u32) -> Vector2<f32> {
+        let half_size = self.half_size();
+        Vector2::new(
+            (x * self.tile_width) as f32 - half_size.x,
+            half_size.y - (y * self.tile_height) as f32,
+        )
+    }
+
+    /// The cell under a world position, given the `GlobalTransform` of the layer.
+    /// `None` when the position is outside the layer.
+    pub fn world_to_tile(
+        &self,
+        global: &GlobalTransform,
+        position: Vector2<f32>,
+    ) -> Option<(u32, u32)> {
+        let inverse = global.0.try_inverse()?;
+        let local = inverse.transform_point(&Point3::new(position.x, position.y, 0.0));
+        self.local_to_tile(Vector2::new(local.x, local.y))
+    }
+
+    /// The cell under a position relative to the centre of the layer.
+    /// `None` when the position is outside the layer.
+    pub fn local_to_tile(&self, position: Vector2<f32>) -> Option<(u32, u32)> {
+        let half_size = self.half_size();
+        let x = ((position.x + half_size.x) / self.tile_width as f32).floor();
+        let y = ((half_size.y - position.y) / self.tile_height as f32).floor();
+        if x >= 0.0 && y >= 0.0 && x < self.width as f32 && y < self.height as f32 {
+            Some((x as u32, y as u32))
+        } else {
+            None
+        }
+    }
+
+    /// World position of a point of a cell, given the `GlobalTransform` of the layer
+    pub fn tile_to_world(
+        &self,
+        global: &GlobalTransform,
+        x: u32,
+        y: u32,
+        anchor: TileAnchor,
+    ) -> Vector2<f32> {
+        let (offset_x, offset_y) = match anchor {
+            TileAnchor::Center => (0.5, 0.5),
+            TileAnchor::TopLeft => (0.0, 0.0),
+            TileAnchor::TopRight => (1.0, 0.0),
+            TileAnchor::BottomLeft => (0.0, 1.0),
+            TileAnchor::BottomRight => (1.0, 1.0),
+        };
+        let top_left = self.tile_top_left(x, y);
+        let local = Point3::new(
+            top_left.x + offset_x * self.tile_width as f32,
+            top_left.y - offset_y * self.tile_height as f32,
+            0.0,
+        );
+        let world = global.0.transform_point(&local);
+        Vector2::new(world.x, world.y)
+    }
+
+    /// Smallest axis aligned world space rectangle containing a cell, as its minimum and
+    /// maximum corners, given the `GlobalTransform` of the layer
+    pub fn tile_rect(
+        &self,
+        global: &GlobalTransform,
+        x: u32,
+        y: u32,
+    ) -> (Vector2<f32>, Vector2<f32>) {
+        let corners = [
+            TileAnchor::TopLeft,
+            TileAnchor::TopRight,
+            TileAnchor::BottomLeft,
+            TileAnchor::BottomRight,
+        ];
+        bounds(
+            corners
+                .iter()
+                .map(|anchor| self.tile_to_world(global, x, y, *anchor)),
+        )
+    }
+}
+
+/// Smallest axis aligned rectangle containing the points, as its minimum and maximum corners
+pub(crate) fn bounds<I>(points: I) -> (Vector2<f32>, Vector2<f32>)
+where
+    I: IntoIterator<Item = Vector2<f32>>,
+{
+    let mut min = Vector2::repeat(std::f32::MAX);
+    let mut max = Vector2::repeat(std::f32::MIN);
+    for point in points {
+        min.x = min.x.min(point.x);
+        min.y = min.y.min(point.y);
+        max.x = max.x.max(point.x);
+        max.y = max.y.max(point.y);
+    }
+    (min, max)
+}
+
+/// Point of a cell converted by `TilemapDimensions::tile_to_world`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileAnchor {
+    Center,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
 }
 
 impl Component for TilemapDimensions {
     type Storage = DenseVecStorage<Self>;
 }
 
+/// Layout of a tilesheet image and the range of gids drawn from it.
 #[derive(Clone)]
 pub struct TilesheetDimensions {
+    /// Gid of the first tile in the tilesheet
+    pub first_gid: u32,
+    /// Number of tile columns in the tilesheet
+    pub width: u32,
+    /// Number of tile rows in the tilesheet
+    pub height: u32,
+    /// Width of a tile in pixels
+    pub tile_width: u32,
+    /// Height of a tile in pixels
+    pub tile_height: u32,
+    /// Pixels between the edge of the image and the first tiles
+    pub margin: u32,
+    /// Pixels between neighbouring tiles
+    pub spacing: u32,
+    /// Width of the tilesheet image in pixels
+    pub image_width: u32,
+    /// Height of the tilesheet image in pixels
+    pub image_height: u32,
+}
+
+impl TilesheetDimensions {
+    /// Layout of a tileset image, accounting for its margin and spacing like Tiled does
+    pub fn from_tileset(tileset: &tiled::Tileset, image: &tiled::Image) -> Self {
+        let image_width = image.width as u32;
+        let image_height = image.height as u32;
+        let columns = (image_width + tileset.spacing).saturating_sub(tileset.margin * 2)
+            / (tileset.tile_width + tileset.spacing);
+        let rows = (image_height + tileset.spacing).saturating_sub(tileset.margin * 2)
+            / (tileset.tile_height + tileset.spacing);
+        TilesheetDimensions {
+            first_gid: tileset.first_gid,
+            width: columns,
+            height: rows,
+            tile_width: tileset.tile_width,
+            tile_height: tileset.tile_height,
+            margin: tileset.margin,
+            spacing: tileset.spacing,
+            image_width,
+            image_height,
+        }
+    }
+
+    /// Number of tiles in the tilesheet
+    pub fn tile_count(&self) -> u32 {
+        self.width * self.height
+    }
+}
+
+/// A tilesheet texture together with its layout.
+#[derive(Clone)]
+pub struct Tilesheet {
+    pub dimensions: TilesheetDimensions,
+    /// Path of the tilesheet image as written in the map, relative to the map, `write_tmx`
+    /// saves it back. `TilemapBuilder` tilesheets have their path relative to the asset
+    /// directory.
+    pub image_source: String,
+    /// Path of the .tsx file the tileset was loaded from, relative to the map, `None` when it
+    /// is embedded in the map
+    pub source: Option<String>,
+    pub texture: TextureHandle,
+    /// Shared by every layer drawing from the tilesheet
+    pub data: Arc<TilesetData>,
+}
+
+/// The tilesheets of a map, sorted by `first_gid`. A layer draws from the ones it has tiles
+/// from, see `TilemapLayer::sheet_slots`.
+#[derive(Clone, Default)]
+pub struct Tilesheets {
+    pub sheets: Vec<Tilesheet>,
+}
+
+impl Tilesheets {
+    pub fn dimensions(&self) -> Vec<TilesheetDimensions> {
+        self.sheets
+            .iter()
+            .map(|sheet| sheet.dimensions.clone())
+            .collect()
+    }
+
+    /// The tilesheet a gid belongs to. Flip flags in the gid are ignored.
+    pub fn sheet_for_gid(&self, gid: u32) -> Option<&Tilesheet> {
+        let (gid, _) = decode_gid(gid);
+        self.sheets
+            .iter()
+            .rev()
+            .find(|sheet| sheet.dimensions.first_gid <= gid)
+            .filter(|sheet| gid - sheet.dimensions.first_gid < sheet.dimensions.tile_count())
+    }
+
+    /// Data Tiled stores for the tile with the given gid, if any
+    pub fn tile_data(&self, gid: u32) -> Option<&TileData> {
+        let sheet = self.sheet_for_gid(gid)?;
+        let (gid, _) = decode_gid(gid);
+        sheet.data.tiles.get(&(gid - sheet.dimensions.first_gid))
+    }
+
+    /// Custom property of the tile with the given gid
+    pub fn tile_property(&self, gid: u32, name: &str) -> Option<&Property> {
+        self.tile_data(gid)?.properties.get(name)
+    }
+}
+
+impl Component for Tilesheets {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A rectangle of tiles, `x` and `y` being the coordinates of its top left tile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TileRegion {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        TileRegion {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Tile coordinates of every cell of the region that lies inside a `width` x `height` grid
+    pub fn cells(&self, width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+        let xs = self.x.min(width)..self.x.saturating_add(self.width).min(width);
+        let ys = self.y.min(height)..self.y.saturating_add(self.height).min(height);
+        ys.flat_map(move |y| xs.clone().map(move |x| (x, y)))
+    }
+}
+
+/// Settings used when spawning tilemaps. Optional, the defaults are used when the resource
+/// is missing.
+#[derive(Clone, Debug)]
+pub struct TilemapSettings {
+    /// Width and height in tiles of the chunks layers are split into. Smaller chunks are culled
+    /// more tightly and uploaded faster when edited, larger ones take fewer draw calls.
+    pub chunk_size: u32,
+}
+
+impl Default for TilemapSettings {
+    fn default() -> Self {
+        TilemapSettings {
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+}
+
+/// A rectangular piece of a layer, drawn with its own mesh and tile texture so that it is
+/// culled and uploaded on its own. Chunk entities are children of their layer entity.
+/// `x` and `y` are the tile coordinates of the top left corner of the chunk.
+#[derive(Clone, Debug)]
+pub struct TilemapChunk {
+    pub layer: Entity,
+    pub x: u32,
+    pub y: u32,
     pub width: u32,
     pub height: u32,
 }
 
-impl Component for TilesheetDimensions {
+impl Component for TilemapChunk {
     type Storage = DenseVecStorage<Self>;
 }
 
+/// Tiles of a whole layer in row order, starting with the top row.
+/// The layer entity holds no mesh, it is drawn through its `TilemapChunk` entities.
+/// Tile coordinates count columns from the left and rows from the top, like in Tiled.
+/// Tiles are changed through `set_tile` and `fill_rect`, which keep track of the chunks that
+/// need to be uploaded again.
 #[derive(Clone)]
 pub struct TilemapLayer {
     pub name: String,
-    pub tiles: Vec<[f32; 4]>,
+    pub width: u32,
+    pub height: u32,
+    pub properties: Properties,
+    /// Position of the layer among the tile and object layers of its map, 0 for the bottom
+    /// one. Orders layers at the same depth.
+    pub map_index: usize,
+    /// Opacity set in Tiled, kept for `write_tmx`
+    pub opacity: f32,
+    /// Visibility set in Tiled, kept for `write_tmx`
+    pub visible: bool,
+    /// Gids of the tiles as stored in the map, flip flags included. 0 is an empty cell.
+    gids: Vec<u32>,
+    /// Shader entries of the tiles, see `tile_entry`
+    tiles: Vec<[f32; 4]>,
+    /// Tilesheets the shader entries are computed from
+    tilesheets: Vec<TilesheetDimensions>,
+    /// Indices in `tilesheets` of the tilesheets bound when drawing the layer
+    sheet_slots: Vec<usize>,
+    /// Size of the `TilemapChunk`s the layer is drawn with
+    chunk_size: u32,
+    /// Incremented whenever a gid changes
+    revision: u64,
+    /// Counters incremented whenever a shader entry of a chunk changes, chunks in row order
+    chunk_revisions: Vec<u64>,
+}
+
+impl TilemapLayer {
+    /// Creates a layer from its gids in row order. `tilesheets` must be sorted by `first_gid`.
+    pub fn new(
+        name: &str,
+        width: u32,
+        height: u32,
+        gids: Vec<u32>,
+        tilesheets: Vec<TilesheetDimensions>,
+        chunk_size: u32,
+        properties: Properties,
+    ) -> Self {
+        let chunk_size = chunk_size.max(1);
+        let chunk_count =
+            ((width + chunk_size - 1) / chunk_size) * ((height + chunk_size - 1) / chunk_size);
+        let sheet_slots = used_tilesheets(&tilesheets, &gids);
+        TilemapLayer {
+            name: name.to_owned(),
+            width,
+            height,
+            properties,
+            map_index: 0,
+            opacity: 1.0,
+            visible: true,
+            tiles: gids
+                .iter()
+                .map(|gid| slot_entry(&tilesheets, &sheet_slots, *gid))
+                .collect(),
+            gids,
+            tilesheets,
+            sheet_slots,
+            chunk_size,
+            revision: 0,
+            chunk_revisions: vec![0; chunk_count as usize],
+        }
+    }
+
+    /// Index of a cell in `gids` and `tiles`
+    pub fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((y * self.width + x) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Gids of every cell in row order, flip flags included
+    pub fn gids(&self) -> &[u32] {
+        &self.gids
+    }
+
+    /// Shader entries of every cell in row order
+    pub fn tiles(&self) -> &[[f32; 4]] {
+        &self.tiles
+    }
+
+    /// Indices in `Tilesheets::sheets` of the tilesheets the layer draws from, in the order
+    /// of the z channel of its shader entries. Only the first `MAX_TILESHEETS` are drawn.
+    pub fn sheet_slots(&self) -> &[usize] {
+        &self.sheet_slots
+    }
+
+    /// Gid of the tile in a cell, flip flags included. 0 is an empty cell.
+    pub fn get_tile(&self, x: u32, y: u32) -> Option<u32> {
+        self.index(x, y).map(|index| self.gids[index])
+    }
+
+    /// Puts the tile with the given gid, flip flags included, in a cell. A tilesheet the layer
+    /// didn't draw from yet is given the next slot.
+    /// Returns the gid that was there, or `None` if the cell is outside the layer.
+    pub fn set_tile(&mut self, x: u32, y: u32, gid: u32) -> Option<u32> {
+        let index = self.index(x, y)?;
+        let previous = self.gids[index];
+        if previous != gid {
+            self.gids[index] = gid;
+            self.revision += 1;
+            if let Some((sheet_index, _)) = tilesheet_for_gid(&self.tilesheets, decode_gid(gid).0) {
+                if !self.sheet_slots.contains(&sheet_index) {
+                    self.sheet_slots.push(sheet_index);
+                    if self.sheet_slots.len() > MAX_TILESHEETS {
+                        warn!(
+                            "Layer {} draws from more than {} tilesheets, tiles of the others \
+                             are not drawn",
+                            self.name, MAX_TILESHEETS
+                        );
+                    }
+                }
+            }
+            let entry = self.entry(gid);
+            self.set_entry(index, entry);
+        }
+        Some(previous)
+    }
+
+    /// Puts the tile with the given gid in every cell of a region
+    pub fn fill_rect(&mut self, region: TileRegion, gid: u32) {
+        for (x, y) in region.cells(self.width, self.height) {
+            self.set_tile(x, y, gid);
+        }
+    }
+
+    /// Shader entry of the tile with the given gid, flip flags included, in this layer
+    pub(crate) fn entry(&self, gid: u32) -> [f32; 4] {
+        slot_entry(&self.tilesheets, &self.sheet_slots, gid)
+    }
+
+    /// Changes the shader entry of a cell without changing its gid, to show another tile
+    /// in its place
+    pub(crate) fn set_entry(&mut self, index: usize, entry: [f32; 4]) {
+        if self.tiles[index] != entry {
+            self.tiles[index] = entry;
+            let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+            let chunks_x = (self.width + self.chunk_size - 1) / self.chunk_size;
+            let chunk = (y / self.chunk_size) * chunks_x + x / self.chunk_size;
+            self.chunk_revisions[chunk as usize] += 1;
+        }
+    }
+
+    /// Counter incremented whenever a gid of the layer changes, for caches built from them
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Counter incremented whenever a tile drawn by a chunk of the layer changes
+    pub fn chunk_revision(&self, chunk: &TilemapChunk) -> u64 {
+        let chunks_x = (self.width + self.chunk_size - 1) / self.chunk_size;
+        let index = (chunk.y / self.chunk_size) * chunks_x + chunk.x / self.chunk_size;
+        self.chunk_revisions
+            .get(index as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Custom property of the tile in a cell
+    pub fn tile_property<'a>(
+        &self,
+        tilesheets: &'a Tilesheets,
+        x: u32,
+        y: u32,
+        name: &str,
+    ) -> Option<&'a Property> {
+        tilesheets.tile_property(self.get_tile(x, y)?, name)
+    }
 }
 
 impl Component for TilemapLayer {
     type Storage = DenseVecStorage<Self>;
 }
+
+#[cfg(test)]
+mod tests {
+    use amethyst::core::nalgebra::Matrix4;
+
+    use super::*;
+
+    /// `count` tilesheets of 2x2 tiles, one after the other from gid 1
+    fn tilesheets(count: u32) -> Vec<TilesheetDimensions> {
+        (0..count)
+            .map(|index| TilesheetDimensions {
+                first_gid: 1 + index * 4,
+                width: 2,
+                height: 2,
+                tile_width: 16,
+                tile_height: 16,
+                margin: 0,
+                spacing: 0,
+                image_width: 32,
+                image_height: 32,
+            })
+            .collect()
+    }
+
+    /// 10x6 cells in chunks of 4, so 3 columns and 2 rows of chunks
+    fn layer() -> TilemapLayer {
+        TilemapLayer::new(
+            "ground",
+            10,
+            6,
+            vec![0; 60],
+            tilesheets(1),
+            4,
+            Properties::new(),
+        )
+    }
+
+    #[test]
+    fn set_tile_returns_the_previous_gid() {
+        let mut layer = layer();
+        assert_eq!(layer.set_tile(2, 1, 3), Some(0));
+        assert_eq!(layer.get_tile(2, 1), Some(3));
+        assert_eq!(layer.tiles()[12], [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(layer.set_tile(2, 1, 0), Some(3));
+        assert_eq!(layer.tiles()[12], [-1.0, -1.0, 0.0, 0.0]);
+
+        // Cells outside the layer
+        assert_eq!(layer.set_tile(10, 0, 3), None);
+        assert_eq!(layer.set_tile(0, 6, 3), None);
+        assert!(layer.gids().iter().all(|gid| *gid == 0));
+    }
+
+    #[test]
+    fn fill_rect_is_clipped_to_the_layer() {
+        let mut layer = layer();
+        layer.fill_rect(TileRegion::new(8, 4, 5, 5), 2);
+        let filled = (0..6)
+            .flat_map(|y| (0..10).map(move |x| (x, y)))
+            .filter(|&(x, y)| layer.get_tile(x, y) == Some(2))
+            .collect::<Vec<_>>();
+        assert_eq!(filled, vec![(8, 4), (9, 4), (8, 5), (9, 5)]);
+    }
+
+    #[test]
+    fn edits_only_bump_the_chunks_they_touch() {
+        let mut layer = layer();
+        let chunks = generate_chunks(World::new().create_entity().build(), 10, 6, 4);
+        let revisions = |layer: &TilemapLayer| {
+            chunks
+                .iter()
+                .map(|chunk| layer.chunk_revision(chunk))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(revisions(&layer), vec![0; 6]);
+
+        layer.set_tile(5, 1, 1);
+        assert_eq!(layer.revision(), 1);
+        assert_eq!(revisions(&layer), vec![0, 1, 0, 0, 0, 0]);
+
+        // Putting the same tile again changes nothing
+        layer.set_tile(5, 1, 1);
+        assert_eq!(layer.revision(), 1);
+        assert_eq!(revisions(&layer), vec![0, 1, 0, 0, 0, 0]);
+
+        // A region across two chunks of the bottom row
+        layer.fill_rect(TileRegion::new(3, 4, 2, 1), 2);
+        assert_eq!(layer.revision(), 3);
+        assert_eq!(revisions(&layer), vec![0, 1, 0, 1, 1, 0]);
+
+        // Entries changed without changing the gid, as animations do
+        layer.set_entry(9, [1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(layer.revision(), 3);
+        assert_eq!(revisions(&layer), vec![0, 1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn tiles_round_trip_through_world_positions() {
+        // An odd number of tiles of an odd width, so the centre falls between two pixels
+        let dimensions = TilemapDimensions {
+            width: 3,
+            height: 5,
+            tile_width: 15,
+            tile_height: 16,
+        };
+        let half_size = dimensions.half_size();
+        assert_eq!(half_size, Vector2::new(22.5, 40.0));
+
+        // Layers are spawned centred on half their size, with their bottom left corner at the
+        // origin of their parent
+        let global = GlobalTransform(Matrix4::new_translation(&Vector3::new(
+            half_size.x,
+            half_size.y,
+            0.0,
+        )));
+        assert_eq!(
+            dimensions.tile_to_world(&global, 0, 4, TileAnchor::BottomLeft),
+            Vector2::new(0.0, 0.0)
+        );
+        assert_eq!(
+            dimensions.tile_to_world(&global, 2, 0, TileAnchor::TopRight),
+            Vector2::new(45.0, 80.0)
+        );
+
+        for y in 0..dimensions.height {
+            for x in 0..dimensions.width {
+                for &anchor in &[TileAnchor::Center, TileAnchor::TopLeft] {
+                    let position = dimensions.tile_to_world(&global, x, y, anchor);
+                    assert_eq!(
+                        dimensions.world_to_tile(&global, position),
+                        Some((x, y)),
+                        "{:?} of ({}, {})",
+                        anchor,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+
+        // Positions outside the layer, the right and bottom edges included
+        for &(x, y) in &[(-0.5, 10.0), (45.0, 10.0), (10.0, 80.5), (10.0, 0.0)] {
+            assert_eq!(dimensions.world_to_tile(&global, Vector2::new(x, y)), None);
+        }
+        assert_eq!(
+            dimensions.world_to_tile(&global, Vector2::new(44.5, 0.5)),
+            Some((2, 4))
+        );
+    }
+
+    #[test]
+    fn planes_cover_odd_sizes() {
+        let plane = generate_tilemap_plane(15, 16, 3, 1);
+        assert_eq!(plane.len(), 6);
+        for vertex in &plane {
+            assert_eq!(vertex.position.x.abs(), 22.5);
+            assert_eq!(vertex.position.y.abs(), 8.0);
+            // Tile coordinates, counting rows from the top
+            let tile = (vertex.tex_coord.x, vertex.tex_coord.y);
+            let expected = (
+                if vertex.position.x < 0.0 { 0.0 } else { 3.0 },
+                if vertex.position.y < 0.0 { 1.0 } else { 0.0 },
+            );
+            assert_eq!(tile, expected);
+        }
+    }
+
+    #[test]
+    fn layers_bind_the_tilesheets_they_use() {
+        // Tiles from the second and the sixth tilesheet
+        let layer = TilemapLayer::new(
+            "ground",
+            3,
+            1,
+            vec![6, 0, 23],
+            tilesheets(6),
+            4,
+            Properties::new(),
+        );
+        assert_eq!(layer.sheet_slots(), &[1, 5]);
+        assert_eq!(
+            layer.tiles(),
+            &[
+                [1.0, 0.0, 0.0, 0.0],
+                [-1.0, -1.0, 0.0, 0.0],
+                [0.0, 1.0, 1.0, 0.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn new_tilesheets_take_the_next_slot() {
+        let mut layer = TilemapLayer::new(
+            "ground",
+            2,
+            1,
+            vec![6, 0],
+            tilesheets(6),
+            4,
+            Properties::new(),
+        );
+        layer.set_tile(1, 0, 13);
+        assert_eq!(layer.sheet_slots(), &[1, 3]);
+        assert_eq!(layer.tiles()[1], [0.0, 0.0, 1.0, 0.0]);
+        // Tiles already placed keep their slot
+        assert_eq!(layer.tiles()[0], [1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn tilesheets_past_the_last_slot_are_not_drawn() {
+        let layer = TilemapLayer::new(
+            "ground",
+            5,
+            1,
+            vec![1, 5, 9, 13, 17],
+            tilesheets(5),
+            4,
+            Properties::new(),
+        );
+        assert_eq!(layer.sheet_slots().len(), MAX_TILESHEETS + 1);
+        assert_eq!(layer.tiles()[3], [0.0, 0.0, 3.0, 0.0]);
+        assert_eq!(layer.tiles()[4], [-1.0, -1.0, 0.0, 0.0]);
+    }
+}
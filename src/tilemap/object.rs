@@ -0,0 +1,244 @@
+use amethyst::core::nalgebra::Vector2;
+use amethyst::core::transform::Parent;
+use amethyst::core::{GlobalTransform, Transform};
+use amethyst::ecs::{Component, DenseVecStorage, Entity};
+use amethyst::prelude::*;
+
+use super::{apply_object_factory, convert_properties, Properties, TiledMap, TilemapError};
+
+/// Shape of a Tiled object, relative to the position of its entity with y pointing up
+#[derive(Clone, Debug, PartialEq)]
+pub enum ObjectShape {
+    /// Rectangle with its top left corner at the object position,
+    /// or its bottom left corner for tile objects
+    Rect {
+        width: f32,
+        height: f32,
+    },
+    /// Ellipse inscribed in the rectangle of the same size
+    Ellipse {
+        width: f32,
+        height: f32,
+    },
+    Polyline {
+        points: Vec<Vector2<f32>>,
+    },
+    Polygon {
+        points: Vec<Vector2<f32>>,
+    },
+    Point,
+}
+
+impl ObjectShape {
+    fn from_tiled(shape: &tiled::ObjectShape, attributes: &ObjectAttributes) -> Self {
+        // `tiled` reads points as empty rectangles
+        if attributes.point {
+            return ObjectShape::Point;
+        }
+
+        let points = |points: &[(f32, f32)]| {
+            points
+                .iter()
+                .map(|&(x, y)| Vector2::new(x, -y))
+                .collect::<Vec<_>>()
+        };
+        match shape {
+            tiled::ObjectShape::Rect { width, height } => ObjectShape::Rect {
+                width: *width,
+                height: *height,
+            },
+            tiled::ObjectShape::Ellipse { width, height } => ObjectShape::Ellipse {
+                width: *width,
+                height: *height,
+            },
+            tiled::ObjectShape::Polyline { points: p } => {
+                ObjectShape::Polyline { points: points(p) }
+            }
+            tiled::ObjectShape::Polygon { points: p } => ObjectShape::Polygon { points: points(p) },
+        }
+    }
+}
+
+/// Attributes of a map object that `tiled` doesn't read, read by `parse_map` instead
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectAttributes {
+    pub width: f32,
+    pub height: f32,
+    /// Clockwise rotation in degrees
+    pub rotation: f32,
+    pub visible: bool,
+    /// Whether the object is a point, which `tiled` reads as an empty rectangle
+    pub point: bool,
+}
+
+impl Default for ObjectAttributes {
+    fn default() -> Self {
+        ObjectAttributes {
+            width: 0.0,
+            height: 0.0,
+            rotation: 0.0,
+            visible: true,
+            point: false,
+        }
+    }
+}
+
+/// Attributes of a map object layer that `tiled` doesn't read, read by `parse_map` instead
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectGroupAttributes {
+    /// Position of the layer among the tile and object layers of the map, 0 for the bottom one
+    pub map_index: usize,
+    pub properties: Properties,
+    /// Colour Tiled draws the objects of the layer with, as written in the map
+    pub color: Option<String>,
+}
+
+/// An object layer of a map, on an entity of its own so that `write_tmx` can save the layer
+/// back even when it has no objects
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectLayer {
+    pub name: String,
+    /// Position of the layer among the tile and object layers of the map, 0 for the bottom one
+    pub map_index: usize,
+    pub opacity: f32,
+    pub visible: bool,
+    /// Colour Tiled draws the objects of the layer with, such as `#ff0000`
+    pub color: Option<String>,
+    pub properties: Properties,
+}
+
+impl Component for ObjectLayer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl ObjectLayer {
+    fn from_tiled(group: &tiled::ObjectGroup, attributes: &ObjectGroupAttributes) -> Self {
+        ObjectLayer {
+            name: group.name.clone(),
+            map_index: attributes.map_index,
+            opacity: group.opacity,
+            visible: group.visible,
+            color: attributes.color.clone(),
+            properties: attributes.properties.clone(),
+        }
+    }
+}
+
+/// An object placed in a Tiled object layer.
+/// The entity holding it has a `Transform` at the object position, in the same space as the
+/// tile layers of the map.
+#[derive(Clone, Debug)]
+pub struct TiledObject {
+    /// Unique id of the object in the map
+    pub id: u32,
+    pub name: String,
+    /// Type of the object as set in Tiled
+    pub object_type: String,
+    /// Name of the object layer the object was placed in
+    pub group: String,
+    /// `ObjectLayer::map_index` of the object layer the object was placed in
+    pub map_index: usize,
+    /// Gid of the tile drawn for tile objects, 0 otherwise
+    pub gid: u32,
+    pub width: f32,
+    pub height: f32,
+    /// Clockwise rotation in degrees, as set in Tiled
+    pub rotation: f32,
+    pub visible: bool,
+    pub shape: ObjectShape,
+    pub properties: Properties,
+}
+
+impl Component for TiledObject {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl TiledObject {
+    fn from_tiled(
+        object: &tiled::Object,
+        group: &ObjectLayer,
+        attributes: &ObjectAttributes,
+    ) -> Self {
+        TiledObject {
+            id: object.id,
+            name: object.name.clone(),
+            object_type: object.obj_type.clone(),
+            group: group.name.clone(),
+            map_index: group.map_index,
+            gid: object.gid,
+            width: attributes.width,
+            height: attributes.height,
+            rotation: attributes.rotation,
+            visible: attributes.visible,
+            shape: ObjectShape::from_tiled(&object.shape, attributes),
+            properties: convert_properties(&object.properties),
+        }
+    }
+}
+
+/// Transform of an object, converting its Tiled pixel position (y pointing down from the top
+/// of the map) to the space the tile layers are placed in.
+pub fn object_transform(
+    map: &tiled::Map,
+    object: &tiled::Object,
+    attributes: &ObjectAttributes,
+) -> Transform {
+    let mut transform = Transform::default();
+    transform.set_x(object.x);
+    transform.set_y((map.height * map.tile_height) as f32 - object.y);
+    transform.set_rotation_euler(0.0, 0.0, -attributes.rotation.to_radians());
+    transform
+}
+
+/// Creates an entity for each object layer of the map holding its `ObjectLayer`, and an
+/// entity for each of its objects, running the factory registered in `ObjectFactories` for
+/// its type.
+/// When `group_names` is given, only the object layers it names are spawned.
+pub fn spawn_objects(
+    world: &mut World,
+    tiled_map: &TiledMap,
+    parent: Option<Entity>,
+    group_names: Option<&[String]>,
+) -> Result<Vec<Entity>, TilemapError> {
+    let map = &tiled_map.map;
+    let mut attributes = tiled_map.objects.iter();
+    let mut entities = Vec::new();
+    for (index, group) in map.object_groups.iter().enumerate() {
+        let group_attributes = attributes
+            .by_ref()
+            .take(group.objects.len())
+            .collect::<Vec<_>>();
+        if let Some(group_names) = group_names {
+            if !group_names.iter().any(|name| *name == group.name) {
+                continue;
+            }
+        }
+
+        let layer = ObjectLayer::from_tiled(
+            group,
+            &tiled_map
+                .object_groups
+                .get(index)
+                .cloned()
+                .unwrap_or_default(),
+        );
+        entities.push(world.create_entity().with(layer.clone()).build());
+
+        for (object, attributes) in group.objects.iter().zip(group_attributes) {
+            let tiled_object = TiledObject::from_tiled(object, &layer, attributes);
+            let mut builder = world
+                .create_entity()
+                .with(object_transform(map, object, attributes))
+                .with(GlobalTransform::default())
+                .with(tiled_object.clone());
+            if let Some(parent) = parent {
+                builder = builder.with(Parent { entity: parent });
+            }
+            let entity = builder.build();
+
+            apply_object_factory(world, entity, &tiled_object)?;
+            entities.push(entity);
+        }
+    }
+    Ok(entities)
+}
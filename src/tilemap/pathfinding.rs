@@ -0,0 +1,476 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+
+use amethyst::core::nalgebra::Vector2;
+use amethyst::core::GlobalTransform;
+use amethyst::ecs::Entity;
+
+use super::{TileAnchor, TilemapDimensions, TilemapLayer, Tilesheets};
+
+/// At most this many paths are cached by a `Pathfinder`, the cache is cleared when it's full
+const MAX_CACHED_PATHS: usize = 1024;
+
+/// Which cells of a layer can be walked through, and how much it costs to enter them.
+/// Costs should be at least 1, so that distances are a lower bound of the cost of a path.
+#[derive(Clone)]
+pub enum Walkability {
+    /// The layer marks obstacles: cells holding a tile are blocked, empty cells cost 1
+    Blocking,
+    /// Cells are walkable unless their tile has the bool property `walkable` set to false.
+    /// The cost of a cell is the `cost` property of its tile, 1 if it has none.
+    /// Empty cells cost 1.
+    Properties { walkable: String, cost: String },
+    /// Cost of the cell at (x, y) holding the given gid, `None` if it's blocked
+    Custom(Arc<dyn Fn(&Tilesheets, u32, u32, u32) -> Option<f32> + Send + Sync>),
+}
+
+impl Walkability {
+    /// The `walkable` and `cost` tile properties
+    pub fn properties() -> Self {
+        Walkability::Properties {
+            walkable: "walkable".to_owned(),
+            cost: "cost".to_owned(),
+        }
+    }
+
+    /// Cost of entering a cell, `None` when it's blocked or outside the layer
+    pub fn cost(
+        &self,
+        layer: &TilemapLayer,
+        tilesheets: &Tilesheets,
+        x: u32,
+        y: u32,
+    ) -> Option<f32> {
+        let gid = layer.get_tile(x, y)?;
+        match self {
+            Walkability::Blocking => {
+                if gid == 0 {
+                    Some(1.0)
+                } else {
+                    None
+                }
+            }
+            Walkability::Properties { walkable, cost } => {
+                let walkable = tilesheets
+                    .tile_property(gid, walkable)
+                    .and_then(|property| property.as_bool())
+                    .unwrap_or(true);
+                if walkable {
+                    Some(
+                        tilesheets
+                            .tile_property(gid, cost)
+                            .and_then(|property| property.as_float())
+                            .unwrap_or(1.0),
+                    )
+                } else {
+                    None
+                }
+            }
+            Walkability::Custom(cost) => cost(tilesheets, x, y, gid),
+        }
+    }
+}
+
+/// Cells a path can move to from a cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Neighbours {
+    /// Left, right, up and down
+    Four,
+    /// The four orthogonal cells and the four diagonal ones
+    Eight,
+}
+
+/// When a diagonal move is allowed next to blocked cells
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CornerCutting {
+    /// Both cells beside the move must be walkable
+    Never,
+    /// One of the cells beside the move may be blocked
+    OneBlocked,
+    /// Diagonal moves are allowed whatever the cells beside them are
+    Always,
+}
+
+/// Neighbours of a cell along with the distance to them, honouring the corner cutting rule
+pub(crate) fn neighbours<'a, F>(
+    neighbours: Neighbours,
+    corner_cutting: CornerCutting,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+    walkable: F,
+) -> impl Iterator<Item = ((u32, u32), f32)> + 'a
+where
+    F: Fn(u32, u32) -> bool + 'a,
+{
+    const OFFSETS: [(i64, i64); 8] = [
+        (1, 0),
+        (-1, 0),
+        (0, 1),
+        (0, -1),
+        (1, 1),
+        (1, -1),
+        (-1, 1),
+        (-1, -1),
+    ];
+    let count = match neighbours {
+        Neighbours::Four => 4,
+        Neighbours::Eight => 8,
+    };
+    let inside =
+        move |x: i64, y: i64| x >= 0 && y >= 0 && x < i64::from(width) && y < i64::from(height);
+
+    OFFSETS[..count].iter().filter_map(move |&(dx, dy)| {
+        let (nx, ny) = (i64::from(x) + dx, i64::from(y) + dy);
+        if !inside(nx, ny) || !walkable(nx as u32, ny as u32) {
+            return None;
+        }
+        if dx == 0 || dy == 0 {
+            return Some(((nx as u32, ny as u32), 1.0));
+        }
+
+        let beside = [(nx, i64::from(y)), (i64::from(x), ny)]
+            .iter()
+            .filter(|&&(bx, by)| walkable(bx as u32, by as u32))
+            .count();
+        let allowed = match corner_cutting {
+            CornerCutting::Never => beside == 2,
+            CornerCutting::OneBlocked => beside >= 1,
+            CornerCutting::Always => true,
+        };
+        if allowed {
+            Some(((nx as u32, ny as u32), std::f32::consts::SQRT_2))
+        } else {
+            None
+        }
+    })
+}
+
+/// Open cell of the A* search, ordered so the `BinaryHeap` pops the lowest estimate first
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Open {
+    pub(crate) estimate: f32,
+    pub(crate) cell: (u32, u32),
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// A* search over the cells of a tile layer.
+/// Found paths are cached for each layer entity until the layer is edited.
+#[derive(Clone)]
+pub struct Pathfinder {
+    pub neighbours: Neighbours,
+    pub corner_cutting: CornerCutting,
+    pub walkability: Walkability,
+    /// Cached paths of each layer entity, with the `TilemapLayer::revision` they were found at
+    cache: HashMap<Entity, (u64, PathCache)>,
+}
+
+/// Paths found between pairs of cells, `None` when there is none
+type PathCache = HashMap<((u32, u32), (u32, u32)), Option<Vec<(u32, u32)>>>;
+
+impl Pathfinder {
+    pub fn new(
+        neighbours: Neighbours,
+        corner_cutting: CornerCutting,
+        walkability: Walkability,
+    ) -> Self {
+        Pathfinder {
+            neighbours,
+            corner_cutting,
+            walkability,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Forgets every cached path, needed after changing the settings of the pathfinder
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Cells of the cheapest path from `start` to `goal`, both included.
+    /// `None` when `goal` can't be reached. `entity` is the entity holding `layer`.
+    pub fn find_path(
+        &mut self,
+        entity: Entity,
+        layer: &TilemapLayer,
+        tilesheets: &Tilesheets,
+        start: (u32, u32),
+        goal: (u32, u32),
+    ) -> Option<Vec<(u32, u32)>> {
+        let revision = layer.revision();
+        let cached = match self.cache.get(&entity) {
+            Some((cached_revision, paths)) if *cached_revision == revision => {
+                paths.get(&(start, goal)).cloned()
+            }
+            _ => None,
+        };
+        if let Some(path) = cached {
+            return path;
+        }
+
+        let path = self.search(layer, tilesheets, start, goal);
+
+        let entry = self
+            .cache
+            .entry(entity)
+            .or_insert_with(|| (revision, HashMap::new()));
+        if entry.0 != revision || entry.1.len() >= MAX_CACHED_PATHS {
+            *entry = (revision, HashMap::new());
+        }
+        entry.1.insert((start, goal), path.clone());
+        path
+    }
+
+    /// World positions of the centres of the cells of the cheapest path between the cells
+    /// under `start` and `goal`. `global` is the `GlobalTransform` of the layer.
+    pub fn find_world_path(
+        &mut self,
+        entity: Entity,
+        layer: &TilemapLayer,
+        tilesheets: &Tilesheets,
+        dimensions: &TilemapDimensions,
+        global: &GlobalTransform,
+        start: Vector2<f32>,
+        goal: Vector2<f32>,
+    ) -> Option<Vec<Vector2<f32>>> {
+        let start = dimensions.world_to_tile(global, start)?;
+        let goal = dimensions.world_to_tile(global, goal)?;
+        let path = self.find_path(entity, layer, tilesheets, start, goal)?;
+        Some(
+            path.into_iter()
+                .map(|(x, y)| dimensions.tile_to_world(global, x, y, TileAnchor::Center))
+                .collect(),
+        )
+    }
+
+    fn heuristic(&self, (x, y): (u32, u32), (goal_x, goal_y): (u32, u32)) -> f32 {
+        let dx = (x as f32 - goal_x as f32).abs();
+        let dy = (y as f32 - goal_y as f32).abs();
+        match self.neighbours {
+            Neighbours::Four => dx + dy,
+            Neighbours::Eight => dx.max(dy) + (std::f32::consts::SQRT_2 - 1.0) * dx.min(dy),
+        }
+    }
+
+    fn search(
+        &self,
+        layer: &TilemapLayer,
+        tilesheets: &Tilesheets,
+        start: (u32, u32),
+        goal: (u32, u32),
+    ) -> Option<Vec<(u32, u32)>> {
+        let cost = |(x, y): (u32, u32)| self.walkability.cost(layer, tilesheets, x, y);
+        cost(start)?;
+        cost(goal)?;
+
+        let mut open = BinaryHeap::new();
+        let mut costs: HashMap<(u32, u32), f32> = HashMap::new();
+        let mut came_from: HashMap<(u32, u32), (u32, u32)> = HashMap::new();
+        costs.insert(start, 0.0);
+        open.push(Open {
+            estimate: self.heuristic(start, goal),
+            cell: start,
+        });
+
+        while let Some(Open { estimate, cell }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![goal];
+                let mut cell = goal;
+                while let Some(previous) = came_from.get(&cell) {
+                    path.push(*previous);
+                    cell = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            let cell_cost = costs[&cell];
+            // Stale entry of a cell that was reached more cheaply since
+            if estimate > cell_cost + self.heuristic(cell, goal) {
+                continue;
+            }
+
+            let walkable = |x, y| cost((x, y)).is_some();
+            for (next, distance) in neighbours(
+                self.neighbours,
+                self.corner_cutting,
+                cell,
+                (layer.width, layer.height),
+                walkable,
+            ) {
+                let next_cost = cell_cost + distance * cost(next).unwrap_or(1.0);
+                if costs.get(&next).map_or(true, |known| next_cost < *known) {
+                    costs.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Open {
+                        estimate: next_cost + self.heuristic(next, goal),
+                        cell: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use amethyst::prelude::*;
+
+    use super::super::Properties;
+    use super::*;
+
+    const WALL: u32 = 1;
+
+    /// An empty layer with walls in the given cells
+    fn layer(width: u32, height: u32, walls: &[(u32, u32)]) -> TilemapLayer {
+        let mut layer = TilemapLayer::new(
+            "ground",
+            width,
+            height,
+            vec![0; (width * height) as usize],
+            Vec::new(),
+            4,
+            Properties::new(),
+        );
+        for &(x, y) in walls {
+            layer.set_tile(x, y, WALL);
+        }
+        layer
+    }
+
+    fn entity() -> Entity {
+        World::new().create_entity().build()
+    }
+
+    fn find_path(
+        neighbours: Neighbours,
+        corner_cutting: CornerCutting,
+        layer: &TilemapLayer,
+        start: (u32, u32),
+        goal: (u32, u32),
+    ) -> Option<Vec<(u32, u32)>> {
+        Pathfinder::new(neighbours, corner_cutting, Walkability::Blocking).find_path(
+            entity(),
+            layer,
+            &Tilesheets::default(),
+            start,
+            goal,
+        )
+    }
+
+    #[test]
+    fn paths_move_to_four_or_eight_neighbours() {
+        let layer = layer(5, 5, &[]);
+        let path = find_path(
+            Neighbours::Four,
+            CornerCutting::Never,
+            &layer,
+            (0, 0),
+            (4, 4),
+        )
+        .expect("Path is found");
+        assert_eq!(path.len(), 9);
+        assert_eq!((path[0], path[8]), ((0, 0), (4, 4)));
+        for step in path.windows(2) {
+            let (dx, dy) = (
+                (step[0].0 as i32 - step[1].0 as i32).abs(),
+                (step[0].1 as i32 - step[1].1 as i32).abs(),
+            );
+            assert_eq!(dx + dy, 1, "{:?}", step);
+        }
+
+        let path = find_path(
+            Neighbours::Eight,
+            CornerCutting::Never,
+            &layer,
+            (0, 0),
+            (4, 4),
+        );
+        assert_eq!(path, Some(vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]));
+    }
+
+    #[test]
+    fn diagonals_follow_the_corner_cutting_rule() {
+        // One of the cells beside the diagonal is blocked
+        let layer = layer(2, 2, &[(1, 0)]);
+        let path =
+            |corner_cutting| find_path(Neighbours::Eight, corner_cutting, &layer, (0, 0), (1, 1));
+        assert_eq!(
+            path(CornerCutting::Never),
+            Some(vec![(0, 0), (0, 1), (1, 1)])
+        );
+        assert_eq!(path(CornerCutting::OneBlocked), Some(vec![(0, 0), (1, 1)]));
+        assert_eq!(path(CornerCutting::Always), Some(vec![(0, 0), (1, 1)]));
+
+        // Both are blocked
+        let layer = self::layer(2, 2, &[(1, 0), (0, 1)]);
+        let path =
+            |corner_cutting| find_path(Neighbours::Eight, corner_cutting, &layer, (0, 0), (1, 1));
+        assert_eq!(path(CornerCutting::Never), None);
+        assert_eq!(path(CornerCutting::OneBlocked), None);
+        assert_eq!(path(CornerCutting::Always), Some(vec![(0, 0), (1, 1)]));
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        // A wall splits the layer in two
+        let layer = layer(5, 3, &[(2, 0), (2, 1), (2, 2), (4, 0)]);
+        for &neighbours in &[Neighbours::Four, Neighbours::Eight] {
+            let path =
+                |start, goal| find_path(neighbours, CornerCutting::Always, &layer, start, goal);
+            assert_eq!(path((0, 1), (4, 1)), None);
+            // Goals on a wall or outside the layer
+            assert_eq!(path((3, 1), (4, 0)), None);
+            assert_eq!(path((0, 1), (5, 1)), None);
+            assert!(path((0, 0), (1, 2)).is_some());
+        }
+    }
+
+    #[test]
+    fn cached_paths_are_found_again_once_the_layer_changes() {
+        let entity = entity();
+        let tilesheets = Tilesheets::default();
+        let mut layer = layer(3, 3, &[]);
+        let mut pathfinder = Pathfinder::new(
+            Neighbours::Four,
+            CornerCutting::Never,
+            Walkability::Blocking,
+        );
+        assert_eq!(
+            pathfinder.find_path(entity, &layer, &tilesheets, (0, 0), (2, 0)),
+            Some(vec![(0, 0), (1, 0), (2, 0)])
+        );
+
+        // The cached path is kept while the layer is unchanged, even when every cell is
+        // blocked for the new walkability
+        pathfinder.walkability = Walkability::Custom(Arc::new(|_, _, _, _| None));
+        assert_eq!(
+            pathfinder.find_path(entity, &layer, &tilesheets, (0, 0), (2, 0)),
+            Some(vec![(0, 0), (1, 0), (2, 0)])
+        );
+
+        pathfinder.walkability = Walkability::Blocking;
+        layer.set_tile(1, 0, WALL);
+        assert_eq!(
+            pathfinder.find_path(entity, &layer, &tilesheets, (0, 0), (2, 0)),
+            Some(vec![(0, 0), (0, 1), (1, 1), (2, 1), (2, 0)])
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use amethyst::core::nalgebra::{Point3, Vector2};
+use amethyst::core::specs::prelude::{
+    Entities, Entity, Join, Read, ReadExpect, ReadStorage, System, Write,
+};
+use amethyst::core::GlobalTransform;
+use amethyst::input::InputHandler;
+use amethyst::renderer::{ActiveCamera, Camera, ScreenDimensions};
+
+use super::{TilemapDimensions, TilemapLayer};
+
+/// A cell of a layer found under a screen position
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileHit {
+    /// The layer entity
+    pub layer: Entity,
+    pub x: u32,
+    pub y: u32,
+    /// Where the ray through the screen position crosses the layer, in world space
+    pub position: Point3<f32>,
+}
+
+/// The tile under the mouse cursor, updated by `TilePickSystem`.
+/// `None` when the cursor is outside the window or not over any layer.
+#[derive(Clone, Debug, Default)]
+pub struct HoveredTile(pub Option<TileHit>);
+
+/// The active camera with its transform, or the first camera when none is active
+pub(crate) fn active_camera<'a>(
+    active: Option<&ActiveCamera>,
+    cameras: &'a ReadStorage<'_, Camera>,
+    globals: &'a ReadStorage<'_, GlobalTransform>,
+) -> Option<(&'a Camera, &'a GlobalTransform)> {
+    active
+        .and_then(|active| {
+            let entity = active.entity?;
+            Some((cameras.get(entity)?, globals.get(entity)?))
+        })
+        .or_else(|| (cameras, globals).join().next())
+}
+
+/// Two world space points a screen position is drawn from: one on the near plane of the
+/// camera and one on the far plane.
+/// `screen` is in pixels from the top left corner of a screen of `screen_size` pixels.
+pub fn screen_ray(
+    camera: &Camera,
+    transform: &GlobalTransform,
+    screen: Vector2<f32>,
+    screen_size: Vector2<f32>,
+) -> Option<(Point3<f32>, Point3<f32>)> {
+    let view = transform.0.try_inverse()?;
+    let inverse = (camera.proj * view).try_inverse()?;
+    let ndc_x = screen.x / screen_size.x * 2.0 - 1.0;
+    let ndc_y = 1.0 - screen.y / screen_size.y * 2.0;
+    Some((
+        inverse.transform_point(&Point3::new(ndc_x, ndc_y, -1.0)),
+        inverse.transform_point(&Point3::new(ndc_x, ndc_y, 1.0)),
+    ))
+}
+
+/// Where a ray from `near` to `far` crosses a layer, as the fraction of the way from `near` to
+/// `far` and the position relative to the centre of the layer.
+/// `global` is the `GlobalTransform` of the layer.
+fn cross_layer(
+    global: &GlobalTransform,
+    near: &Point3<f32>,
+    far: &Point3<f32>,
+) -> Option<(f32, Vector2<f32>)> {
+    let inverse = global.0.try_inverse()?;
+    let near = inverse.transform_point(near);
+    let far = inverse.transform_point(far);
+    let dz = far.z - near.z;
+    if dz.abs() < std::f32::EPSILON {
+        return None;
+    }
+
+    let t = -near.z / dz;
+    let local = near + (far - near) * t;
+    Some((t, Vector2::new(local.x, local.y)))
+}
+
+/// Every layer cell crossed by a ray from `near` to `far`, closest to `near` first.
+/// `layers` holds each layer entity with its dimensions, transform and `TilemapLayer::map_index`.
+/// Layers at the same depth are ordered like they are in their map, topmost first.
+pub fn pick_tiles<'a, I>(near: &Point3<f32>, far: &Point3<f32>, layers: I) -> Vec<TileHit>
+where
+    I: IntoIterator<Item = (Entity, &'a TilemapDimensions, &'a GlobalTransform, usize)>,
+{
+    let mut hits = layers
+        .into_iter()
+        .filter_map(|(layer, dimensions, global, order)| {
+            let (t, local) = cross_layer(global, near, far)?;
+            let (x, y) = dimensions.local_to_tile(local)?;
+            let position = *near + (*far - *near) * t;
+            Some((
+                t,
+                order,
+                TileHit {
+                    layer,
+                    x,
+                    y,
+                    position,
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.1.cmp(&a.1))
+    });
+    hits.into_iter().map(|(_, _, hit)| hit).collect()
+}
+
+/// Finds the tile under the mouse cursor through the active camera, or the first camera when
+/// none is active, and publishes it as the `HoveredTile` resource.
+/// Cells holding a tile are preferred over empty cells of layers above them.
+/// `AX` and `AC` are the axis and action types of the `InputHandler`, so the system is added
+/// after the input system:
+/// ```ignore
+/// .with(TilePickSystem::<String, String>::default(), "tile_pick", &["input_system"])
+/// ```
+pub struct TilePickSystem<AX, AC> {
+    _pd: PhantomData<(AX, AC)>,
+}
+
+impl<AX, AC> Default for TilePickSystem<AX, AC> {
+    fn default() -> Self {
+        TilePickSystem { _pd: PhantomData }
+    }
+}
+
+impl<'a, AX, AC> System<'a> for TilePickSystem<AX, AC>
+where
+    AX: Hash + Eq + Clone + Send + Sync + 'static,
+    AC: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, InputHandler<AX, AC>>,
+        ReadExpect<'a, ScreenDimensions>,
+        Option<Read<'a, ActiveCamera>>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, TilemapDimensions>,
+        ReadStorage<'a, TilemapLayer>,
+        Write<'a, HoveredTile>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, input, screen, active, camera, global, dimensions, layers, mut hovered) =
+            data;
+        hovered.0 = None;
+
+        let camera = active_camera(active.as_ref().map(|active| &**active), &camera, &global);
+        let (camera, transform) = match camera {
+            Some(camera) => camera,
+            None => return,
+        };
+        let (mouse_x, mouse_y) = match input.mouse_position() {
+            Some(position) => position,
+            None => return,
+        };
+
+        let (near, far) = match screen_ray(
+            camera,
+            transform,
+            Vector2::new(mouse_x as f32, mouse_y as f32),
+            Vector2::new(screen.width(), screen.height()),
+        ) {
+            Some(ray) => ray,
+            None => return,
+        };
+
+        let hits = pick_tiles(
+            &near,
+            &far,
+            (&*entities, &dimensions, &global, &layers).join().map(
+                |(entity, dimensions, global, layer)| (entity, dimensions, global, layer.map_index),
+            ),
+        );
+        let tile = hits
+            .iter()
+            .find(|hit| {
+                layers
+                    .get(hit.layer)
+                    .and_then(|layer| layer.get_tile(hit.x, hit.y))
+                    .map_or(false, |gid| gid != 0)
+            })
+            .or_else(|| hits.first());
+        hovered.0 = tile.cloned();
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::VecDeque;
+
+use super::{TerrainGrid, TileRegion};
+
+/// Small xorshift64* random number generator. The same seed always gives the same numbers,
+/// on every platform, so generated maps can be recreated from their seed.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Scramble the seed with splitmix64, xorshift needs a non zero state and similar seeds
+        // would otherwise start out alike
+        let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        state ^= state >> 31;
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Number in `0.0..1.0`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Number in `low..high`, `low` when the range is empty
+    pub fn range(&mut self, low: u32, high: u32) -> u32 {
+        if high <= low {
+            return low;
+        }
+        low + (self.next_u64() % u64::from(high - low)) as u32
+    }
+
+    /// `true` with the given probability
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
+
+/// What a generator made of a cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cell {
+    Wall,
+    Floor,
+}
+
+/// Cells made by a generator, in row order from the top left corner like tile layers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grid {
+    pub width: u32,
+    pub height: u32,
+    cells: Vec<Cell>,
+}
+
+impl Grid {
+    /// Grid of `width` x `height` cells all set to `cell`
+    pub fn new(width: u32, height: u32, cell: Cell) -> Self {
+        Grid {
+            width,
+            height,
+            cells: vec![cell; (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Option<Cell> {
+        if x < self.width && y < self.height {
+            Some(self.cells[(y * self.width + x) as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[(y * self.width + x) as usize] = cell;
+        }
+    }
+
+    /// Whether a cell is a wall, cells outside the grid being walls
+    pub fn is_wall(&self, x: i64, y: i64) -> bool {
+        if x < 0 || y < 0 || x >= i64::from(self.width) || y >= i64::from(self.height) {
+            return true;
+        }
+        self.cells[(y as u32 * self.width + x as u32) as usize] == Cell::Wall
+    }
+
+    /// Number of floor cells
+    pub fn floor_count(&self) -> usize {
+        self.cells
+            .iter()
+            .filter(|cell| **cell == Cell::Floor)
+            .count()
+    }
+
+    /// Gids of every cell in row order, for `TilemapLayer::new`
+    pub fn gids<F>(&self, gid: F) -> Vec<u32>
+    where
+        F: Fn(Cell) -> u32,
+    {
+        self.cells.iter().map(|cell| gid(*cell)).collect()
+    }
+
+    /// Terrain grid for an `Autotiler`, giving every cell the terrain `terrain` maps it to
+    pub fn terrain<T, F>(&self, terrain: F) -> TerrainGrid<T>
+    where
+        T: Clone + PartialEq,
+        F: Fn(Cell) -> Option<T>,
+    {
+        TerrainGrid::from_fn(self.width, self.height, |x, y| {
+            terrain(self.cells[(y * self.width + x) as usize])
+        })
+    }
+
+    /// Turns every floor cell that can't be reached from the largest area of floor, moving
+    /// orthogonally, into a wall
+    pub fn keep_largest_region(&mut self) {
+        let mut region = vec![None; self.cells.len()];
+        let mut sizes = Vec::new();
+        for start in 0..self.cells.len() {
+            if self.cells[start] != Cell::Floor || region[start].is_some() {
+                continue;
+            }
+
+            let id = sizes.len();
+            let mut size = 0;
+            let mut open = VecDeque::new();
+            region[start] = Some(id);
+            open.push_back(start);
+            while let Some(index) = open.pop_front() {
+                size += 1;
+                let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+                for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    let (nx, ny) = (i64::from(x) + dx, i64::from(y) + dy);
+                    if self.is_wall(nx, ny) {
+                        continue;
+                    }
+                    let next = (ny as u32 * self.width + nx as u32) as usize;
+                    if region[next].is_none() {
+                        region[next] = Some(id);
+                        open.push_back(next);
+                    }
+                }
+            }
+            sizes.push(size);
+        }
+
+        let largest = (0..sizes.len()).max_by_key(|id| sizes[*id]);
+        for (cell, region) in self.cells.iter_mut().zip(region) {
+            if region.is_some() && region != largest {
+                *cell = Cell::Wall;
+            }
+        }
+    }
+
+    /// Number of walls among the eight neighbours of a cell
+    fn walls_around(&self, x: u32, y: u32) -> usize {
+        let mut walls = 0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if (dx != 0 || dy != 0) && self.is_wall(i64::from(x) + dx, i64::from(y) + dy) {
+                    walls += 1;
+                }
+            }
+        }
+        walls
+    }
+
+    /// Makes floor of every cell of a region
+    fn carve(&mut self, region: TileRegion) {
+        for (x, y) in region.cells(self.width, self.height) {
+            self.set(x, y, Cell::Floor);
+        }
+    }
+}
+
+/// Settings of `caves`
+#[derive(Clone, Copy, Debug)]
+pub struct CaveSettings {
+    /// Probability of a cell starting as a wall
+    pub fill: f32,
+    /// Number of smoothing steps
+    pub iterations: u32,
+    /// A floor cell becomes a wall when at least this many of its neighbours are walls
+    pub birth: usize,
+    /// A wall stays a wall when at least this many of its neighbours are walls
+    pub survival: usize,
+    /// Whether to only keep the largest connected cave
+    pub connected: bool,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        CaveSettings {
+            fill: 0.45,
+            iterations: 5,
+            birth: 5,
+            survival: 4,
+            connected: true,
+        }
+    }
+}
+
+/// Caves made by a cellular automaton: cells start as random walls and floor, then each step
+/// turns cells surrounded by walls into walls and the others into floor.
+/// The border of the grid is always wall.
+pub fn caves(width: u32, height: u32, settings: &CaveSettings, rng: &mut Rng) -> Grid {
+    let mut grid = Grid::new(width, height, Cell::Wall);
+    let border = |x: u32, y: u32| x == 0 || y == 0 || x + 1 >= width || y + 1 >= height;
+    for y in 0..height {
+        for x in 0..width {
+            if !border(x, y) && !rng.chance(settings.fill) {
+                grid.set(x, y, Cell::Floor);
+            }
+        }
+    }
+
+    for _ in 0..settings.iterations {
+        let mut next = grid.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let walls = grid.walls_around(x, y);
+                let wall = border(x, y)
+                    || match grid.get(x, y) {
+                        Some(Cell::Wall) => walls >= settings.survival,
+                        _ => walls >= settings.birth,
+                    };
+                next.set(x, y, if wall { Cell::Wall } else { Cell::Floor });
+            }
+        }
+        grid = next;
+    }
+
+    if settings.connected {
+        grid.keep_largest_region();
+    }
+    grid
+}
+
+/// Settings of `bsp_dungeon`
+#[derive(Clone, Copy, Debug)]
+pub struct BspSettings {
+    /// Smallest width and height of the areas the map is split into
+    pub min_leaf: u32,
+    /// Smallest width and height of a room
+    pub min_room: u32,
+    /// How many times the map is split at most
+    pub max_depth: u32,
+}
+
+impl Default for BspSettings {
+    fn default() -> Self {
+        BspSettings {
+            min_leaf: 8,
+            min_room: 4,
+            max_depth: 6,
+        }
+    }
+}
+
+/// Dungeon made by `bsp_dungeon`
+#[derive(Clone, Debug)]
+pub struct Dungeon {
+    pub grid: Grid,
+    /// Rooms in the order their areas were split
+    pub rooms: Vec<TileRegion>,
+}
+
+/// Rooms and corridors dungeon: the map is split in two again and again (binary space
+/// partitioning), a room is placed in each area and the rooms of the two halves of every
+/// split are joined by a corridor, so every room can be reached.
+pub fn bsp_dungeon(width: u32, height: u32, settings: &BspSettings, rng: &mut Rng) -> Dungeon {
+    let mut dungeon = Dungeon {
+        grid: Grid::new(width, height, Cell::Wall),
+        rooms: Vec::new(),
+    };
+    if width >= 3 && height >= 3 {
+        // Keep a wall around the map
+        let area = TileRegion::new(1, 1, width - 2, height - 2);
+        split(&mut dungeon, area, settings, settings.max_depth, rng);
+    }
+    dungeon
+}
+
+/// Fills an area of a BSP dungeon and returns the rooms placed in it
+fn split(
+    dungeon: &mut Dungeon,
+    area: TileRegion,
+    settings: &BspSettings,
+    depth: u32,
+    rng: &mut Rng,
+) -> Vec<TileRegion> {
+    let min_leaf = settings.min_leaf.max(settings.min_room + 2).max(1);
+    let can_split_x = area.width >= min_leaf * 2;
+    let can_split_y = area.height >= min_leaf * 2;
+    let split_x = match (can_split_x, can_split_y) {
+        _ if depth == 0 => None,
+        (true, true) => Some(if area.width == area.height {
+            rng.chance(0.5)
+        } else {
+            // Split across the longer side, so areas don't get too thin
+            area.width > area.height
+        }),
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    };
+
+    let (first, second) = match split_x {
+        Some(true) => {
+            let at = rng.range(min_leaf, area.width - min_leaf + 1);
+            (
+                TileRegion::new(area.x, area.y, at, area.height),
+                TileRegion::new(area.x + at, area.y, area.width - at, area.height),
+            )
+        }
+        Some(false) => {
+            let at = rng.range(min_leaf, area.height - min_leaf + 1);
+            (
+                TileRegion::new(area.x, area.y, area.width, at),
+                TileRegion::new(area.x, area.y + at, area.width, area.height - at),
+            )
+        }
+        None => {
+            let room = place_room(area, settings.min_room, rng);
+            dungeon.grid.carve(room);
+            dungeon.rooms.push(room);
+            return vec![room];
+        }
+    };
+
+    let mut rooms = split(dungeon, first, settings, depth - 1, rng);
+    let second_rooms = split(dungeon, second, settings, depth - 1, rng);
+    let from = rooms[rng.range(0, rooms.len() as u32) as usize];
+    let to = second_rooms[rng.range(0, second_rooms.len() as u32) as usize];
+    corridor(&mut dungeon.grid, centre(from), centre(to), rng);
+    rooms.extend(second_rooms);
+    rooms
+}
+
+/// Random room inside an area, leaving a wall between it and the edges of the area
+fn place_room(area: TileRegion, min_room: u32, rng: &mut Rng) -> TileRegion {
+    let max_width = area.width.saturating_sub(2).max(1);
+    let max_height = area.height.saturating_sub(2).max(1);
+    let width = rng.range(min_room.min(max_width), max_width + 1);
+    let height = rng.range(min_room.min(max_height), max_height + 1);
+    let x = area.x + rng.range(1, area.width - width).min(area.width - width);
+    let y = area.y + rng.range(1, area.height - height).min(area.height - height);
+    TileRegion::new(x, y, width, height)
+}
+
+fn centre(room: TileRegion) -> (u32, u32) {
+    (room.x + room.width / 2, room.y + room.height / 2)
+}
+
+/// Carves an L shaped corridor between two cells, going horizontally or vertically first
+fn corridor(grid: &mut Grid, from: (u32, u32), to: (u32, u32), rng: &mut Rng) {
+    let corner = if rng.chance(0.5) {
+        (to.0, from.1)
+    } else {
+        (from.0, to.1)
+    };
+    for &(start, end) in &[(from, corner), (corner, to)] {
+        let region = TileRegion::new(
+            start.0.min(end.0),
+            start.1.min(end.1),
+            (i64::from(start.0) - i64::from(end.0)).abs() as u32 + 1,
+            (i64::from(start.1) - i64::from(end.1)).abs() as u32 + 1,
+        );
+        grid.carve(region);
+    }
+}
+
+/// Settings of `random_walk`
+#[derive(Clone, Copy, Debug)]
+pub struct WalkSettings {
+    /// The walk stops once this part of the cells is floor
+    pub floor_ratio: f32,
+    /// The walk stops after this many steps even if it didn't carve enough floor
+    pub max_steps: u32,
+    /// Cell the walk starts at, the centre of the grid when `None`
+    pub start: Option<(u32, u32)>,
+}
+
+impl Default for WalkSettings {
+    fn default() -> Self {
+        WalkSettings {
+            floor_ratio: 0.4,
+            max_steps: 100_000,
+            start: None,
+        }
+    }
+}
+
+/// Drunkard walk: a walker wanders in random directions, carving floor wherever it steps.
+/// The border of the grid is always wall.
+pub fn random_walk(width: u32, height: u32, settings: &WalkSettings, rng: &mut Rng) -> Grid {
+    let mut grid = Grid::new(width, height, Cell::Wall);
+    if width < 3 || height < 3 {
+        return grid;
+    }
+
+    let (mut x, mut y) = settings.start.unwrap_or((width / 2, height / 2));
+    x = x.max(1).min(width - 2);
+    y = y.max(1).min(height - 2);
+    let wanted = ((width - 2) * (height - 2)) as f32 * settings.floor_ratio.min(1.0);
+    let mut floor = 0;
+    for _ in 0..settings.max_steps {
+        if grid.get(x, y) == Some(Cell::Wall) {
+            grid.set(x, y, Cell::Floor);
+            floor += 1;
+        }
+        if floor as f32 >= wanted {
+            break;
+        }
+
+        match rng.range(0, 4) {
+            0 if x > 1 => x -= 1,
+            1 if x < width - 2 => x += 1,
+            2 if y > 1 => y -= 1,
+            3 if y < height - 2 => y += 1,
+            _ => {}
+        }
+    }
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeds_give_the_same_numbers() {
+        let mut rng = Rng::new(42);
+        assert_eq!(rng.next_u64(), 0x31b0_ece7_c4f6_97a2);
+        assert_eq!(rng.next_u64(), 0x9008_a3b1_cb68_6f03);
+        assert_eq!(rng.next_u64(), 0x7c71_73ab_d97b_e16f);
+
+        let (mut first, mut second) = (Rng::new(7), Rng::new(7));
+        for _ in 0..100 {
+            assert_eq!(first.next_u64(), second.next_u64());
+        }
+        let (mut first, mut second) = (Rng::new(0), Rng::new(1));
+        assert_ne!(first.next_u64(), second.next_u64());
+    }
+
+    #[test]
+    fn numbers_stay_in_range() {
+        let mut rng = Rng::new(3);
+        for _ in 0..1000 {
+            let number = rng.next_f32();
+            assert!(number >= 0.0 && number < 1.0);
+            let number = rng.range(5, 9);
+            assert!(number >= 5 && number < 9);
+        }
+        assert_eq!(rng.range(4, 4), 4);
+    }
+
+    #[test]
+    fn seeds_give_the_same_maps() {
+        let cave = |seed| caves(40, 30, &CaveSettings::default(), &mut Rng::new(seed));
+        assert_eq!(cave(11), cave(11));
+        assert_ne!(cave(11), cave(12));
+
+        let dungeon = |seed| bsp_dungeon(48, 32, &BspSettings::default(), &mut Rng::new(seed));
+        let (first, second) = (dungeon(5), dungeon(5));
+        assert_eq!(first.grid, second.grid);
+        assert_eq!(first.rooms, second.rooms);
+
+        let walk = |seed| random_walk(30, 20, &WalkSettings::default(), &mut Rng::new(seed));
+        assert_eq!(walk(9), walk(9));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use amethyst::ecs::{Component, DenseVecStorage};
+
+use tiled::PropertyValue;
+
+/// `tiled` doesn't know about file properties, so `parse_map` turns them into string
+/// properties starting with this marker, which `Property::from_tiled` strips again.
+pub(crate) const FILE_PROPERTY_MARKER: &str = "\u{E000}file:";
+
+/// Value of a custom property set in Tiled
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+    String(String),
+    /// Colour as ARGB
+    Color(u32),
+    /// Path of a file, relative to the map or tileset the property was set in
+    File(String),
+}
+
+impl Property {
+    pub fn from_tiled(value: &PropertyValue) -> Self {
+        match value {
+            PropertyValue::BoolValue(value) => Property::Bool(*value),
+            PropertyValue::IntValue(value) => Property::Int(*value),
+            PropertyValue::FloatValue(value) => Property::Float(*value),
+            PropertyValue::ColorValue(value) => Property::Color(*value),
+            PropertyValue::StringValue(value) => {
+                if value.starts_with(FILE_PROPERTY_MARKER) {
+                    Property::File(value[FILE_PROPERTY_MARKER.len()..].to_owned())
+                } else {
+                    Property::String(value.clone())
+                }
+            }
+        }
+    }
+
+    /// Parses a property given its `type` and `value` attributes, `None` for unknown types
+    /// and malformed values
+    pub(crate) fn parse(property_type: Option<&str>, value: &str) -> Option<Self> {
+        match property_type.unwrap_or("string") {
+            "bool" => value.parse().ok().map(Property::Bool),
+            "int" => value.parse().ok().map(Property::Int),
+            "float" => value.parse().ok().map(Property::Float),
+            "string" => Some(Property::String(value.to_owned())),
+            // Written as #AARRGGBB, or #RRGGBB for opaque colours by older versions
+            "color" if value.len() == 7 => u32::from_str_radix(&value[1..], 16)
+                .ok()
+                .map(|color| Property::Color(0xff00_0000 | color)),
+            "color" if value.len() > 1 => u32::from_str_radix(&value[1..], 16)
+                .ok()
+                .map(Property::Color),
+            "file" => Some(Property::File(value.to_owned())),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Property::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Property::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Float value of the property, ints are converted
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            Property::Float(value) => Some(*value),
+            Property::Int(value) => Some(*value as f32),
+            _ => None,
+        }
+    }
+
+    /// String value of string and file properties
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Property::String(value) | Property::File(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_color(&self) -> Option<u32> {
+        match self {
+            Property::Color(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+pub type Properties = HashMap<String, Property>;
+
+pub fn convert_properties(properties: &tiled::Properties) -> Properties {
+    properties
+        .iter()
+        .map(|(name, value)| (name.clone(), Property::from_tiled(value)))
+        .collect()
+}
+
+/// Custom properties of the map a layer belongs to
+#[derive(Clone, Debug, Default)]
+pub struct MapProperties(pub Properties);
+
+impl Component for MapProperties {
+    type Storage = DenseVecStorage<Self>;
+}
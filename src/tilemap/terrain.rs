@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+
+use xml::attribute::OwnedAttribute;
+
+use super::tsx::attribute;
+use super::WangTile;
+
+/// Side of a tile another tile can be placed against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Top => Side::Bottom,
+            Side::Right => Side::Left,
+            Side::Bottom => Side::Top,
+            Side::Left => Side::Right,
+        }
+    }
+
+    /// Positions of the Wang id along the side, in the order the opposite side lists the
+    /// positions they touch
+    fn wang_positions(self) -> [usize; 3] {
+        match self {
+            Side::Top => [7, 0, 1],
+            Side::Right => [1, 2, 3],
+            Side::Bottom => [5, 4, 3],
+            Side::Left => [7, 6, 5],
+        }
+    }
+
+    /// Corners of a terrain tile along the side, in the same order as `wang_positions`
+    pub(crate) fn corners(self) -> [usize; 2] {
+        match self {
+            Side::Top => [0, 1],
+            Side::Right => [1, 3],
+            Side::Bottom => [2, 3],
+            Side::Left => [0, 2],
+        }
+    }
+}
+
+/// Terrain type of a tileset, made in Tiled before version 1.5
+#[derive(Clone, Debug, PartialEq)]
+pub struct Terrain {
+    pub name: String,
+    /// Id of the tile standing for the terrain in Tiled
+    pub tile: Option<u32>,
+}
+
+/// Terrains of the corners of a tile: top left, top right, bottom left and bottom right,
+/// as indices into `TilesetData::terrains`
+pub type TileTerrain = [Option<u32>; 4];
+
+/// Parses the `terrain` attribute of a tile: four terrain indices separated by commas,
+/// empty for corners without a terrain
+pub(crate) fn parse_tile_terrain(value: &str) -> Option<TileTerrain> {
+    let mut terrain = [None; 4];
+    let mut corners = value.split(',');
+    for corner in terrain.iter_mut() {
+        let index = corners.next()?.trim();
+        if !index.is_empty() {
+            *corner = Some(index.parse().ok()?);
+        }
+    }
+    Some(terrain)
+}
+
+/// What the edges and corners of the tiles of a Wang set stand for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WangSetType {
+    Corner,
+    Edge,
+    /// Both edges and corners
+    Mixed,
+}
+
+/// Colour of a Wang set, standing for a terrain
+#[derive(Clone, Debug, PartialEq)]
+pub struct WangColor {
+    pub name: String,
+    /// Colour it's shown with in Tiled, as written in the file
+    pub color: String,
+    /// Id of the tile standing for the colour in Tiled
+    pub tile: Option<u32>,
+    /// Relative chance of tiles of this colour being picked by Tiled
+    pub probability: f32,
+}
+
+/// Wang set of a tileset: which colour each edge and corner of its tiles has
+#[derive(Clone, Debug, PartialEq)]
+pub struct WangSet {
+    pub name: String,
+    /// Id of the tile standing for the set in Tiled
+    pub tile: Option<u32>,
+    pub wang_type: WangSetType,
+    /// Colours of the set. Colour `n` of a Wang id is `colors[n - 1]`, 0 means no colour.
+    pub colors: Vec<WangColor>,
+    /// Wang ids of the tiles of the set, keyed by their id in the tileset. Colours go
+    /// clockwise from the top edge like `WangTile::wang_id`.
+    pub tiles: HashMap<u32, [u32; 8]>,
+}
+
+impl WangSet {
+    /// Index of a colour as used in Wang ids
+    pub fn color_index(&self, name: &str) -> Option<u32> {
+        self.colors
+            .iter()
+            .position(|color| color.name == name)
+            .map(|index| index as u32 + 1)
+    }
+
+    /// Whether two tiles of the set join seamlessly when `other` is placed against `side` of
+    /// `tile`: the edge and corners of `tile` along that side have the same colours as those
+    /// of `other` along the opposite side.
+    /// Tiles outside the set don't join anything.
+    pub fn joins(&self, tile: u32, other: u32, side: Side) -> bool {
+        match (self.tiles.get(&tile), self.tiles.get(&other)) {
+            (Some(tile), Some(other)) => side
+                .wang_positions()
+                .iter()
+                .zip(side.opposite().wang_positions().iter())
+                .all(|(position, other_position)| tile[*position] == other[*other_position]),
+            _ => false,
+        }
+    }
+
+    /// Tiles of the set as gids of a tileset starting at `first_gid`, for an
+    /// `AutotileRule::Wang`
+    pub fn wang_tiles(&self, first_gid: u32) -> Vec<WangTile> {
+        let mut tiles = self
+            .tiles
+            .iter()
+            .map(|(id, wang_id)| WangTile {
+                gid: first_gid + id,
+                wang_id: *wang_id,
+            })
+            .collect::<Vec<_>>();
+        // Keep the order stable, autotiling picks the first of equally good tiles
+        tiles.sort_by_key(|tile| tile.gid);
+        tiles
+    }
+}
+
+/// Reads the `<terraintypes>` and `<wangsets>` elements of a tileset from the events inside
+/// them. Malformed entries are skipped.
+#[derive(Default)]
+pub(crate) struct TerrainReader {
+    terrains: Vec<Terrain>,
+    wang_sets: Vec<WangSet>,
+    // Colours of Wang sets made before Tiled 1.5, which kept edges and corners apart
+    edge_colors: Vec<WangColor>,
+    corner_colors: Vec<WangColor>,
+    // Hexadecimal Wang ids of those sets, which need the colours to be converted
+    old_wang_ids: Vec<(u32, u32)>,
+}
+
+impl TerrainReader {
+    pub(crate) fn start(&mut self, name: &str, attributes: &[OwnedAttribute]) {
+        let value = |key: &str| attribute(attributes, key);
+        match name {
+            "terrain" => self.terrains.push(Terrain {
+                name: value("name").unwrap_or("").to_owned(),
+                tile: value("tile").and_then(parse_tile_id),
+            }),
+            "wangset" => self.wang_sets.push(WangSet {
+                name: value("name").unwrap_or("").to_owned(),
+                tile: value("tile").and_then(parse_tile_id),
+                wang_type: match value("type") {
+                    Some("edge") => WangSetType::Edge,
+                    Some("mixed") => WangSetType::Mixed,
+                    _ => WangSetType::Corner,
+                },
+                colors: Vec::new(),
+                tiles: HashMap::new(),
+            }),
+            "wangcolor" | "wangedgecolor" | "wangcornercolor" => {
+                let color = WangColor {
+                    name: value("name").unwrap_or("").to_owned(),
+                    color: value("color").unwrap_or("").to_owned(),
+                    tile: value("tile").and_then(parse_tile_id),
+                    probability: value("probability")
+                        .and_then(|probability| probability.parse().ok())
+                        .unwrap_or(1.0),
+                };
+                match name {
+                    "wangedgecolor" => self.edge_colors.push(color),
+                    "wangcornercolor" => self.corner_colors.push(color),
+                    _ => {
+                        if let Some(wang_set) = self.wang_sets.last_mut() {
+                            wang_set.colors.push(color);
+                        }
+                    }
+                }
+            }
+            "wangtile" => {
+                let (tile_id, wang_id) = match (value("tileid"), value("wangid")) {
+                    (Some(tile_id), Some(wang_id)) => (tile_id, wang_id),
+                    _ => return,
+                };
+                let tile_id = match tile_id.parse() {
+                    Ok(tile_id) => tile_id,
+                    Err(_) => return,
+                };
+                if wang_id.starts_with("0x") {
+                    if let Ok(wang_id) = u32::from_str_radix(&wang_id[2..], 16) {
+                        self.old_wang_ids.push((tile_id, wang_id));
+                    }
+                } else if let (Some(wang_set), Some(wang_id)) =
+                    (self.wang_sets.last_mut(), parse_wang_id(wang_id))
+                {
+                    wang_set.tiles.insert(tile_id, wang_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn end(&mut self, name: &str) {
+        if name == "wangset" {
+            if let Some(wang_set) = self.wang_sets.last_mut() {
+                finish_wang_set(
+                    wang_set,
+                    &mut self.edge_colors,
+                    &mut self.corner_colors,
+                    &mut self.old_wang_ids,
+                );
+            }
+        }
+    }
+
+    /// The terrain types and Wang sets read
+    pub(crate) fn finish(self) -> (Vec<Terrain>, Vec<WangSet>) {
+        (self.terrains, self.wang_sets)
+    }
+}
+
+/// Adds the colours and tiles of a Wang set made before Tiled 1.5. Edge colours come first,
+/// then corner colours, and the type is guessed from which the set has.
+fn finish_wang_set(
+    wang_set: &mut WangSet,
+    edge_colors: &mut Vec<WangColor>,
+    corner_colors: &mut Vec<WangColor>,
+    old_wang_ids: &mut Vec<(u32, u32)>,
+) {
+    if edge_colors.is_empty() && corner_colors.is_empty() {
+        return;
+    }
+
+    let edge_count = edge_colors.len() as u32;
+    wang_set.wang_type = match (edge_colors.is_empty(), corner_colors.is_empty()) {
+        (false, true) => WangSetType::Edge,
+        (true, false) => WangSetType::Corner,
+        _ => WangSetType::Mixed,
+    };
+    wang_set.colors.append(edge_colors);
+    wang_set.colors.append(corner_colors);
+    for (tile_id, old_wang_id) in old_wang_ids.drain(..) {
+        let mut wang_id = [0; 8];
+        for (position, color) in wang_id.iter_mut().enumerate() {
+            // Each position takes a nibble, the top edge being the lowest one
+            let index = (old_wang_id >> (position * 4)) & 0xf;
+            *color = match index {
+                0 => 0,
+                index if position % 2 == 0 => index,
+                index => index + edge_count,
+            };
+        }
+        wang_set.tiles.insert(tile_id, wang_id);
+    }
+}
+
+/// Parses a Wang id written as eight colours separated by commas
+fn parse_wang_id(value: &str) -> Option<[u32; 8]> {
+    let mut wang_id = [0; 8];
+    let mut colors = value.split(',');
+    for color in wang_id.iter_mut() {
+        *color = colors.next()?.trim().parse().ok()?;
+    }
+    Some(wang_id)
+}
+
+/// Tile ids are written as -1 when there is no tile
+fn parse_tile_id(value: &str) -> Option<u32> {
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use xml::reader::{EventReader, XmlEvent};
+
+    use super::*;
+
+    /// Reads the terrain types and Wang sets of a tileset the way `parse_map` does
+    fn read(xml: &str) -> (Vec<Terrain>, Vec<WangSet>) {
+        let mut reader = TerrainReader::default();
+        for event in EventReader::new(xml.as_bytes()) {
+            match event.expect("Test XML is well-formed") {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => reader.start(&name.local_name, &attributes),
+                XmlEvent::EndElement { name } => reader.end(&name.local_name),
+                _ => {}
+            }
+        }
+        reader.finish()
+    }
+
+    #[test]
+    fn reads_terrain_types() {
+        let (terrains, wang_sets) = read(
+            r#"<terraintypes>
+                <terrain name="Grass" tile="4"/>
+                <terrain name="Water" tile="-1"/>
+            </terraintypes>"#,
+        );
+        assert_eq!(
+            terrains,
+            vec![
+                Terrain {
+                    name: "Grass".to_owned(),
+                    tile: Some(4),
+                },
+                Terrain {
+                    name: "Water".to_owned(),
+                    tile: None,
+                },
+            ]
+        );
+        assert!(wang_sets.is_empty());
+        assert_eq!(
+            parse_tile_terrain("0,,1,0"),
+            Some([Some(0), None, Some(1), Some(0)])
+        );
+        assert_eq!(parse_tile_terrain("0,1"), None);
+    }
+
+    #[test]
+    fn reads_wang_sets() {
+        let (_, wang_sets) = read(
+            r##"<wangsets>
+                <wangset name="Paths" type="edge" tile="-1">
+                    <wangcolor name="Dirt" color="#ff0000" tile="2" probability="0.5"/>
+                    <wangcolor name="Stone" color="#00ff00" tile="-1" probability="1"/>
+                    <wangtile tileid="3" wangid="1,0,2,0,1,0,2,0"/>
+                    <wangtile tileid="4" wangid="1,0,2"/>
+                </wangset>
+            </wangsets>"##,
+        );
+        assert_eq!(wang_sets.len(), 1);
+        let wang_set = &wang_sets[0];
+        assert_eq!(wang_set.name, "Paths");
+        assert_eq!(wang_set.tile, None);
+        assert_eq!(wang_set.wang_type, WangSetType::Edge);
+        assert_eq!(wang_set.colors[0].tile, Some(2));
+        assert_eq!(wang_set.colors[0].probability, 0.5);
+        assert_eq!(wang_set.color_index("Stone"), Some(2));
+        assert_eq!(wang_set.tiles.len(), 1);
+        assert_eq!(wang_set.tiles[&3], [1, 0, 2, 0, 1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn converts_hex_wang_ids() {
+        let (_, wang_sets) = read(
+            r##"<wangsets>
+                <wangset name="Roads" tile="-1">
+                    <wangedgecolor name="Road" color="#808080" tile="-1" probability="1"/>
+                    <wangcornercolor name="Grass" color="#00ff00" tile="-1" probability="1"/>
+                    <wangcornercolor name="Sand" color="#ffff00" tile="-1" probability="1"/>
+                    <wangtile tileid="0" wangid="0x20102010"/>
+                    <wangtile tileid="1" wangid="0x1"/>
+                </wangset>
+                <wangset name="Coast" tile="-1">
+                    <wangcornercolor name="Water" color="#0000ff" tile="-1" probability="1"/>
+                    <wangtile tileid="5" wangid="0x10101010"/>
+                </wangset>
+            </wangsets>"##,
+        );
+        assert_eq!(wang_sets.len(), 2);
+
+        // Edge colours come first, so corner colours are offset by the number of edge colours
+        let roads = &wang_sets[0];
+        assert_eq!(roads.wang_type, WangSetType::Mixed);
+        let names = roads
+            .colors
+            .iter()
+            .map(|color| color.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Road", "Grass", "Sand"]);
+        assert_eq!(roads.tiles[&0], [0, 2, 0, 3, 0, 2, 0, 3]);
+        assert_eq!(roads.tiles[&1], [1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Colours and tiles of a set don't leak into the next one
+        let coast = &wang_sets[1];
+        assert_eq!(coast.wang_type, WangSetType::Corner);
+        assert_eq!(coast.colors.len(), 1);
+        assert_eq!(coast.tiles.len(), 1);
+        assert_eq!(coast.tiles[&5], [0, 1, 0, 1, 0, 1, 0, 1]);
+    }
+}
@@ -1,32 +1,46 @@
+use std::collections::HashMap;
 use std::marker::PhantomData;
 
 use glsl_layout::*;
 
 use amethyst::assets::AssetStorage;
-use amethyst::core::nalgebra::Matrix4;
+use amethyst::core::nalgebra::{Matrix4, Point3, Vector2};
 use amethyst::core::transform::GlobalTransform;
 
 use amethyst::ecs::ReadStorage;
 
-use amethyst::core::specs::prelude::{Join, Read, ReadExpect};
+use amethyst::core::specs::prelude::{Entities, Entity, Join, Read, ReadExpect};
 
 use amethyst::renderer::error::Result;
 use amethyst::renderer::{
-    ActiveCamera, Camera, Encoder, Factory, Material, MaterialDefaults, Mesh, MeshHandle, Position,
-    Query, TexCoord, Texture,
+    ActiveCamera, Camera, Encoder, Factory, MaterialDefaults, Mesh, MeshHandle, Position, Query,
+    TexCoord, Texture, TextureBuilder,
 };
 
 use amethyst::renderer::pipe::pass::{Pass, PassData};
 use amethyst::renderer::pipe::{Effect, NewEffect};
 
+use gfx::format::{ChannelType, SurfaceType};
+use gfx::texture::{AaMode, FilterMethod, Kind, SamplerInfo, WrapMode};
 use gfx::{preset::blend::ALPHA, pso::buffer::ElemStride};
 use gfx_core::state::ColorMask;
 
-use super::{TilemapDimensions, TilemapLayer, TilesheetDimensions};
+use log::error;
+
+use super::picking::active_camera;
+use super::{bounds, Fog, FogOfWar, TilemapChunk, TilemapDimensions, TilemapLayer, Tilesheets};
 
 const TILEMAP_VERT_SRC: &[u8] = include_bytes!("../../resources/shaders/tilemap_v.glsl");
 const TILEMAP_FRAG_SRC: &[u8] = include_bytes!("../../resources/shaders/tilemap_f.glsl");
 
+/// Width and height in tiles of the chunks layers are split into, unless `TilemapSettings`
+/// says otherwise
+pub const CHUNK_SIZE: u32 = 64;
+
+/// Maximum number of tilesheets a single layer can draw from.
+/// Must match `MAX_TILESHEETS` in `tilemap_f.glsl`.
+pub const MAX_TILESHEETS: usize = 4;
+
 #[derive(Clone, Copy, Debug, Uniform)]
 struct VertexArgs {
     proj: mat4,
@@ -38,22 +52,102 @@ struct VertexArgs {
 #[derive(Clone, Copy, Debug, Uniform)]
 struct FragmentArgs {
     u_world_size: vec4,
-    u_tilesheet_size: vec4,
 }
 
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct TileMapBuffer {
-    u_data: [[f32; 4]; 4096],
+struct TilesheetBuffer {
+    u_tilesheet_layout: [[f32; 4]; MAX_TILESHEETS * 2],
+}
+
+/// Texture holding the tiles of a chunk, one texel per tile, with the revisions of the chunk
+/// and of the fog of war of its layer they are from
+#[derive(Clone, Debug)]
+struct ChunkTexture {
+    texture: Texture,
+    revision: u64,
+    fog_revision: Option<u64>,
+}
+
+/// Axis aligned rectangle in world space
+#[derive(Clone, Copy, Debug)]
+struct Bounds {
+    min: Vector2<f32>,
+    max: Vector2<f32>,
+}
+
+impl Bounds {
+    /// Bounds of the given points after transforming them by `matrix`
+    fn transformed(matrix: &Matrix4<f32>, points: &[Point3<f32>]) -> Self {
+        let (min, max) = bounds(points.iter().map(|point| {
+            let point = matrix.transform_point(point);
+            Vector2::new(point.x, point.y)
+        }));
+        Bounds { min, max }
+    }
+
+    /// Area of the world visible through an orthographic camera
+    fn camera(cam: &Camera, transform: &GlobalTransform) -> Option<Self> {
+        let view = transform.0.try_inverse()?;
+        let inverse = (cam.proj * view).try_inverse()?;
+        Some(Bounds::transformed(
+            &inverse,
+            &[
+                Point3::new(-1.0, -1.0, 0.0),
+                Point3::new(1.0, -1.0, 0.0),
+                Point3::new(-1.0, 1.0, 0.0),
+                Point3::new(1.0, 1.0, 0.0),
+            ],
+        ))
+    }
+
+    /// Area of the world covered by a chunk mesh
+    fn chunk(
+        chunk: &TilemapChunk,
+        dimensions: &TilemapDimensions,
+        global: &GlobalTransform,
+    ) -> Self {
+        let half_width = (chunk.width * dimensions.tile_width) as f32 / 2.0;
+        let half_height = (chunk.height * dimensions.tile_height) as f32 / 2.0;
+        Bounds::transformed(
+            &global.0,
+            &[
+                Point3::new(-half_width, -half_height, 0.0),
+                Point3::new(half_width, -half_height, 0.0),
+                Point3::new(-half_width, half_height, 0.0),
+                Point3::new(half_width, half_height, 0.0),
+            ],
+        )
+    }
+
+    fn overlaps(&self, other: &Bounds) -> bool {
+        self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+    }
 }
 
-/// Draw mesh without lighting
+/// Draw mesh without lighting.
+/// Only chunks inside the bounds of the active camera are drawn.
+/// Each chunk keeps its tiles in its own texture, which is uploaded again only when the tiles
+/// of the chunk or the fog of war of its layer change.
+/// Layers with a `FogOfWar` have their remembered and hidden cells darkened.
 /// `V` is `VertexFormat`
-#[derive(Derivative, Clone, Debug, PartialEq)]
-#[derivative(Default(bound = "V: Query<(Position, TexCoord)>, Self: Pass"))]
+#[derive(Derivative, Clone, Debug)]
+#[derivative(
+    Default(bound = "V: Query<(Position, TexCoord)>, Self: Pass"),
+    PartialEq
+)]
 pub struct DrawTilemap<V> {
     _pd: PhantomData<V>,
     layer_name: String,
+    /// Scratch buffer the tiles of a chunk are gathered in
+    #[derivative(PartialEq = "ignore")]
+    chunk_tiles: Vec<[f32; 4]>,
+    /// Tile textures of the chunk entities drawn so far
+    #[derivative(PartialEq = "ignore")]
+    chunk_textures: HashMap<Entity, ChunkTexture>,
 }
 
 impl<V> DrawTilemap<V>
@@ -74,17 +168,19 @@ where
     V: Query<(Position, TexCoord)>,
 {
     type Data = (
+        Entities<'a>,
         Option<Read<'a, ActiveCamera>>,
         ReadStorage<'a, Camera>,
         Read<'a, AssetStorage<Mesh>>,
         Read<'a, AssetStorage<Texture>>,
         ReadExpect<'a, MaterialDefaults>,
         ReadStorage<'a, MeshHandle>,
-        ReadStorage<'a, Material>,
         ReadStorage<'a, GlobalTransform>,
         ReadStorage<'a, TilemapDimensions>,
-        ReadStorage<'a, TilesheetDimensions>,
+        ReadStorage<'a, Tilesheets>,
         ReadStorage<'a, TilemapLayer>,
+        ReadStorage<'a, TilemapChunk>,
+        ReadStorage<'a, FogOfWar>,
     );
 }
 
@@ -98,9 +194,13 @@ where
             .simple(TILEMAP_VERT_SRC, TILEMAP_FRAG_SRC)
             .with_raw_constant_buffer("VertexArgs", mem::size_of::<VertexArgs>(), 1)
             .with_raw_vertex_buffer(V::QUERIED_ATTRIBUTES, V::size() as ElemStride, 0)
-            .with_raw_constant_buffer("TileMapBuffer", mem::size_of::<TileMapBuffer>(), 1)
             .with_raw_constant_buffer("FragmentArgs", mem::size_of::<FragmentArgs>(), 1)
-            .with_texture("TilesheetTexture")
+            .with_raw_constant_buffer("TilesheetBuffer", mem::size_of::<TilesheetBuffer>(), 1)
+            .with_texture("TilesheetTexture0")
+            .with_texture("TilesheetTexture1")
+            .with_texture("TilesheetTexture2")
+            .with_texture("TilesheetTexture3")
+            .with_texture("TileData")
             .with_blended_output("Color", ColorMask::all(), ALPHA, None)
             .build()
     }
@@ -109,59 +209,66 @@ where
         &'a mut self,
         encoder: &mut Encoder,
         effect: &mut Effect,
-        _factory: Factory,
+        mut factory: Factory,
         (
+            entities,
             active,
             camera,
             mesh_storage,
             tex_storage,
             material_defaults,
             mesh,
-            material,
             global,
             tilemap_dimensions,
-            tilesheet_dimensions,
+            tilesheets,
             tile_layer,
+            tile_chunk,
+            fog_of_war,
         ): (
+            Entities<'a>,
             Option<Read<'a, ActiveCamera>>,
             ReadStorage<'a, Camera>,
             Read<'a, AssetStorage<Mesh>>,
             Read<'a, AssetStorage<Texture>>,
             ReadExpect<'a, MaterialDefaults>,
             ReadStorage<'b, MeshHandle>,
-            ReadStorage<'b, Material>,
             ReadStorage<'b, GlobalTransform>,
             ReadStorage<'b, TilemapDimensions>,
-            ReadStorage<'b, TilesheetDimensions>,
+            ReadStorage<'b, Tilesheets>,
             ReadStorage<'b, TilemapLayer>,
+            ReadStorage<'b, TilemapChunk>,
+            ReadStorage<'b, FogOfWar>,
         ),
     ) {
-        let camera: Option<(&Camera, &GlobalTransform)> = active
-            .and_then(|a| {
-                let cam = camera.get(a.entity?);
-                let transform = global.get(a.entity?);
-                cam.into_iter().zip(transform.into_iter()).next()
-            })
-            .or_else(|| (&camera, &global).join().next());
+        let camera_bounds =
+            active_camera(active.as_ref().map(|active| &**active), &camera, &global)
+                .and_then(|(cam, transform)| Bounds::camera(cam, transform));
 
         let mesh_storage = &mesh_storage;
         let tex_storage = &tex_storage;
         let material_defaults = &material_defaults;
 
-        for (mesh, material, global, tilemap_dimensions, tilesheet_dimensions, tile_layer) in (
-            &mesh,
-            &material,
-            &global,
-            &tilemap_dimensions,
-            &tilesheet_dimensions,
-            &tile_layer,
-        )
-            .join()
-        {
+        for (entity, mesh, global, chunk) in (&*entities, &mesh, &global, &tile_chunk).join() {
+            let (tile_layer, tilemap_dimensions, tilesheets) = match (
+                tile_layer.get(chunk.layer),
+                tilemap_dimensions.get(chunk.layer),
+                tilesheets.get(chunk.layer),
+            ) {
+                (Some(tile_layer), Some(tilemap_dimensions), Some(tilesheets)) => {
+                    (tile_layer, tilemap_dimensions, tilesheets)
+                }
+                _ => continue,
+            };
             if tile_layer.name != self.layer_name {
                 continue;
             }
 
+            if let Some(ref camera_bounds) = camera_bounds {
+                if !camera_bounds.overlaps(&Bounds::chunk(chunk, tilemap_dimensions, global)) {
+                    continue;
+                }
+            }
+
             let mesh = match mesh_storage.get(mesh) {
                 Some(mesh) => mesh,
                 None => continue,
@@ -199,46 +306,138 @@ where
                     }
                 });
 
-            let option_tilesheet_texture = tex_storage
-                .get(&material.albedo)
-                .or_else(|| tex_storage.get(&material_defaults.0.albedo));
+            let default_texture = match tex_storage.get(&material_defaults.0.albedo) {
+                Some(texture) => texture,
+                None => continue,
+            };
 
-            if let Some(tilesheet_texture) = option_tilesheet_texture {
-                //debug!("Updating VertexArgs");
-                effect.update_constant_buffer("VertexArgs", &vertex_args.std140(), encoder);
+            let fog = fog_of_war.get(chunk.layer);
+            let revision = tile_layer.chunk_revision(chunk);
+            let fog_revision = fog.map(FogOfWar::revision);
+            let outdated = self.chunk_textures.get(&entity).map_or(true, |uploaded| {
+                uploaded.revision != revision || uploaded.fog_revision != fog_revision
+            });
+            if outdated {
+                self.chunk_tiles.clear();
+                for row in chunk.y..chunk.y + chunk.height {
+                    let start = (row * tilemap_dimensions.width + chunk.x) as usize;
+                    let end = start + chunk.width as usize;
+                    self.chunk_tiles
+                        .extend_from_slice(&tile_layer.tiles()[start..end]);
+                    // The fog of a cell goes above the flip flags of its w channel.
+                    if let Some(fog) = fog {
+                        let row_tiles = self.chunk_tiles.len() - chunk.width as usize;
+                        for (column, entry) in self.chunk_tiles[row_tiles..].iter_mut().enumerate()
+                        {
+                            let state = fog
+                                .get(chunk.x + column as u32, row)
+                                .unwrap_or(Fog::Visible);
+                            entry[3] += (state as u32 * 8) as f32;
+                        }
+                    }
+                }
+
+                //debug!("Uploading TileData");
+                let texture = TextureBuilder::new(&self.chunk_tiles[..])
+                    .with_kind(Kind::D2(
+                        chunk.width as u16,
+                        chunk.height as u16,
+                        AaMode::Single,
+                    ))
+                    .with_raw_format(SurfaceType::R32_G32_B32_A32)
+                    .with_channel_type(ChannelType::Float)
+                    .with_sampler(SamplerInfo::new(FilterMethod::Scale, WrapMode::Clamp))
+                    .build(&mut factory);
+                match texture {
+                    Ok(texture) => {
+                        self.chunk_textures.insert(
+                            entity,
+                            ChunkTexture {
+                                texture,
+                                revision,
+                                fog_revision,
+                            },
+                        );
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error uploading the tiles of layer {}: {}",
+                            tile_layer.name, e
+                        );
+                        continue;
+                    }
+                }
+            }
+            let tile_data = &self.chunk_textures[&entity].texture;
+
+            //debug!("Updating VertexArgs");
+            effect.update_constant_buffer("VertexArgs", &vertex_args.std140(), encoder);
+
+            // Every texture slot has to be bound, so unused slots get the default albedo.
+            // Each tilesheet takes two entries in the TilesheetBuffer: the image and tile sizes,
+            // then the margin and spacing, all in pixels.
+            let mut tilesheet_layout = [[0.0f32; 4]; MAX_TILESHEETS * 2];
+            for slot in 0..MAX_TILESHEETS {
+                let sheet = tile_layer
+                    .sheet_slots()
+                    .get(slot)
+                    .and_then(|index| tilesheets.sheets.get(*index));
+                let tilesheet_texture = sheet
+                    .and_then(|sheet| tex_storage.get(&sheet.texture))
+                    .unwrap_or(default_texture);
                 effect.data.textures.push(tilesheet_texture.view().clone());
                 effect
                     .data
                     .samplers
                     .push(tilesheet_texture.sampler().clone());
+
+                if let Some(sheet) = sheet {
+                    let dimensions = &sheet.dimensions;
+                    tilesheet_layout[slot * 2] = [
+                        dimensions.image_width as f32,
+                        dimensions.image_height as f32,
+                        dimensions.tile_width as f32,
+                        dimensions.tile_height as f32,
+                    ];
+                    tilesheet_layout[slot * 2 + 1] = [
+                        dimensions.margin as f32,
+                        dimensions.spacing as f32,
+                        0.0,
+                        0.0,
+                    ];
+                }
             }
+            effect.data.textures.push(tile_data.view().clone());
+            effect.data.samplers.push(tile_data.sampler().clone());
 
+            // The z and w channels hold the brightness of remembered and hidden cells.
+            let (remembered_brightness, hidden_brightness) = fog
+                .map(|fog| (fog.remembered_brightness, fog.hidden_brightness))
+                .unwrap_or((1.0, 1.0));
             let fragment_args = FragmentArgs {
                 u_world_size: [
-                    tilemap_dimensions.width as f32,
-                    tilemap_dimensions.height as f32,
-                    0.0,
-                    0.0,
-                ]
-                .into(),
-                u_tilesheet_size: [
-                    tilesheet_dimensions.width as f32,
-                    tilesheet_dimensions.height as f32,
-                    0.0,
-                    0.0,
+                    chunk.width as f32,
+                    chunk.height as f32,
+                    remembered_brightness,
+                    hidden_brightness,
                 ]
                 .into(),
             };
-            //debug!("Updating TileMapBuffer");
-            effect.update_buffer("TileMapBuffer", &tile_layer.tiles[..], encoder);
 
             //debug!("Updating FragmentArgs");
             effect.update_constant_buffer("FragmentArgs", &fragment_args.std140(), encoder);
 
+            //debug!("Updating TilesheetBuffer");
+            effect.update_buffer("TilesheetBuffer", &tilesheet_layout[..], encoder);
+
             effect.data.vertex_bufs.push(vbuf);
 
             effect.draw(mesh.slice(), encoder);
             effect.clear();
         }
+
+        // Forget the textures of deleted chunks
+        self.chunk_textures
+            .retain(|entity, _| entities.is_alive(*entity));
     }
 }
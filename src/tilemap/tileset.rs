@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use super::{AnimationFrame, Properties, Side, Terrain, TileShape, TileTerrain, WangSet};
+
+/// What Tiled knows about a tileset besides its image, read by `parse_map`
+#[derive(Clone, Debug, Default)]
+pub struct TilesetData {
+    pub name: String,
+    pub properties: Properties,
+    /// Tiles with extra data, keyed by their id in the tileset
+    pub tiles: HashMap<u32, TileData>,
+    /// Terrain types, the corners of tiles refer to them by index
+    pub terrains: Vec<Terrain>,
+    pub wang_sets: Vec<WangSet>,
+}
+
+impl TilesetData {
+    pub fn wang_set(&self, name: &str) -> Option<&WangSet> {
+        self.wang_sets.iter().find(|wang_set| wang_set.name == name)
+    }
+
+    /// Terrain of a corner of a tile, see `TileTerrain` for the order of the corners
+    pub fn corner_terrain(&self, tile_id: u32, corner: usize) -> Option<&Terrain> {
+        let index = self.tiles.get(&tile_id)?.terrain?.get(corner).cloned()??;
+        self.terrains.get(index as usize)
+    }
+
+    /// Whether two tiles join seamlessly when `other` is placed against `side` of `tile`,
+    /// going by the terrains of their corners. Tiles without terrains don't join anything.
+    pub fn terrain_joins(&self, tile: u32, other: u32, side: Side) -> bool {
+        let terrain = |id| self.tiles.get(&id).and_then(|tile| tile.terrain);
+        match (terrain(tile), terrain(other)) {
+            (Some(tile), Some(other)) => side
+                .corners()
+                .iter()
+                .zip(side.opposite().corners().iter())
+                .all(|(corner, other_corner)| tile[*corner] == other[*other_corner]),
+            _ => false,
+        }
+    }
+}
+
+/// Data attached to a single tile of a tileset
+#[derive(Clone, Debug, Default)]
+pub struct TileData {
+    /// Type of the tile as set in Tiled
+    pub tile_type: Option<String>,
+    pub properties: Properties,
+    /// Frames of the animation of the tile, empty if it isn't animated
+    pub animation: Vec<AnimationFrame>,
+    /// Terrains of the corners of the tile, if it has any
+    pub terrain: Option<TileTerrain>,
+    /// Shapes drawn in Tiled's collision editor
+    pub collision: Vec<TileShape>,
+}
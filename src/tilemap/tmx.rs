@@ -0,0 +1,1124 @@
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::Path;
+
+use amethyst::core::Transform;
+use amethyst::ecs::Entity;
+use amethyst::prelude::*;
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+use super::tsx::normalize;
+use super::{
+    MapProperties, ObjectLayer, ObjectShape, Properties, Property, TileShape, TiledObject,
+    TilemapDimensions, TilemapError, TilemapLayer, TilesetData, TilesheetDimensions, Tilesheets,
+    WangSetType,
+};
+
+/// Version of the .tmx format written, the first with `<wangcolor>` and comma separated Wang ids
+const TMX_VERSION: &str = "1.5";
+
+/// How the gids of tile layers are stored in a written .tmx file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerEncoding {
+    /// Comma separated values, one row of tiles per line
+    Csv,
+    /// Little endian u32s compressed with zlib, then encoded in base64
+    Base64Zlib,
+}
+
+/// Writes tile layers and objects back to a .tmx map that Tiled and `parse_map` can open.
+/// `layers` are tile and object layer entities, with a `TilemapLayer` or an `ObjectLayer`,
+/// such as those in `TilemapLayers`. They are written in the order of their `map_index`, so
+/// the layers of a loaded map keep their order, tile layers with their gids and flip flags
+/// as they are now. The map size, tilesets and map properties are taken from the first tile
+/// layer. Tilesets loaded from a .tsx file are referenced, the others are embedded in the
+/// map with the properties, animations, terrains, Wang sets and collision shapes of their
+/// tiles.
+/// `objects` are entities with a `TiledObject`, written in the object layer it names and
+/// placed and rotated as their `Transform` is. Objects whose layer isn't in `layers` are
+/// grouped into new object layers by their `group`.
+/// `map_dir` is the directory the map is written to, relative to the directory of the map
+/// the layers were loaded from, or to the asset directory for layers made by
+/// `TilemapBuilder`, so that tilesheet images and .tsx files are referenced relative to the
+/// written map. An empty path keeps them as they were loaded.
+pub fn write_tmx<W: Write>(
+    world: &World,
+    layers: &[Entity],
+    objects: &[Entity],
+    map_dir: &Path,
+    encoding: LayerEncoding,
+    output: W,
+) -> Result<(), TilemapError> {
+    let layer_storage = world.read_storage::<TilemapLayer>();
+    let object_layer_storage = world.read_storage::<ObjectLayer>();
+    let dimension_storage = world.read_storage::<TilemapDimensions>();
+    let tilesheet_storage = world.read_storage::<Tilesheets>();
+    let map_property_storage = world.read_storage::<MapProperties>();
+    let object_storage = world.read_storage::<TiledObject>();
+    let transform_storage = world.read_storage::<Transform>();
+
+    let first = *layers
+        .iter()
+        .find(|entity| layer_storage.contains(**entity))
+        .ok_or(TilemapError::NoLayers)?;
+    let (dimensions, tilesheets) =
+        match (dimension_storage.get(first), tilesheet_storage.get(first)) {
+            (Some(dimensions), Some(tilesheets)) => (dimensions, tilesheets),
+            _ => return Err(TilemapError::MissingTileset),
+        };
+    let map = MapData {
+        dimensions,
+        tilesets: tilesheets
+            .sheets
+            .iter()
+            .map(|sheet| TilesetSource {
+                dimensions: &sheet.dimensions,
+                image_source: &sheet.image_source,
+                source: sheet.source.as_ref().map(String::as_str),
+                data: &sheet.data,
+            })
+            .collect(),
+        properties: map_property_storage
+            .get(first)
+            .map(|properties| &properties.0),
+        layers: layers
+            .iter()
+            .filter_map(|entity| layer_storage.get(*entity))
+            .collect(),
+        object_layers: layers
+            .iter()
+            .filter_map(|entity| object_layer_storage.get(*entity))
+            .collect(),
+        objects: objects
+            .iter()
+            .filter_map(|entity| {
+                let transform = transform_storage.get(*entity)?;
+                Some((object_storage.get(*entity)?, transform))
+            })
+            .collect(),
+    };
+    write_map(&map, map_dir, encoding, output)
+}
+
+/// What `write_tmx` reads from the layer and object entities
+struct MapData<'a> {
+    dimensions: &'a TilemapDimensions,
+    tilesets: Vec<TilesetSource<'a>>,
+    properties: Option<&'a Properties>,
+    layers: Vec<&'a TilemapLayer>,
+    object_layers: Vec<&'a ObjectLayer>,
+    objects: Vec<(&'a TiledObject, &'a Transform)>,
+}
+
+/// A tilesheet of the layers written by `write_tmx`
+struct TilesetSource<'a> {
+    dimensions: &'a TilesheetDimensions,
+    /// Path of the image, see `Tilesheet::image_source`
+    image_source: &'a str,
+    /// Path of the .tsx file, see `Tilesheet::source`. `None` embeds the tileset.
+    source: Option<&'a str>,
+    data: &'a TilesetData,
+}
+
+/// A layer of a written map
+enum MapLayer<'a, 'b> {
+    Tiles(&'a TilemapLayer),
+    /// An object layer with its objects
+    Objects(&'b ObjectGroup<'a>),
+}
+
+/// An object layer of a written map with its objects
+struct ObjectGroup<'a> {
+    layer: Cow<'a, ObjectLayer>,
+    objects: Vec<(&'a TiledObject, &'a Transform)>,
+}
+
+/// Puts objects into the object layers they name, adding the layers that are missing
+fn object_groups<'a>(
+    layers: &[&'a ObjectLayer],
+    objects: &[(&'a TiledObject, &'a Transform)],
+) -> Vec<ObjectGroup<'a>> {
+    let mut groups = layers
+        .iter()
+        .map(|layer| ObjectGroup {
+            layer: Cow::Borrowed(*layer),
+            objects: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    for &(object, transform) in objects {
+        let named = |group: &ObjectGroup<'_>| group.layer.name == object.group;
+        let index = groups
+            .iter()
+            .position(|group| named(group) && group.layer.map_index == object.map_index)
+            .or_else(|| {
+                groups[layers.len()..]
+                    .iter()
+                    .position(named)
+                    .map(|index| index + layers.len())
+            });
+        let index = match index {
+            Some(index) => index,
+            None => {
+                groups.push(ObjectGroup {
+                    layer: Cow::Owned(ObjectLayer {
+                        name: object.group.clone(),
+                        map_index: object.map_index,
+                        opacity: 1.0,
+                        visible: true,
+                        color: None,
+                        properties: Properties::new(),
+                    }),
+                    objects: Vec::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        groups[index].objects.push((object, transform));
+    }
+    groups
+}
+
+fn write_map<W: Write>(
+    map: &MapData<'_>,
+    map_dir: &Path,
+    encoding: LayerEncoding,
+    output: W,
+) -> Result<(), TilemapError> {
+    let dimensions = map.dimensions;
+    let next_object_id = map
+        .objects
+        .iter()
+        .map(|(object, _)| object.id)
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(output);
+    writer.write(
+        XmlEvent::start_element("map")
+            .attr("version", TMX_VERSION)
+            .attr("orientation", "orthogonal")
+            .attr("renderorder", "right-down")
+            .attr("width", &dimensions.width.to_string())
+            .attr("height", &dimensions.height.to_string())
+            .attr("tilewidth", &dimensions.tile_width.to_string())
+            .attr("tileheight", &dimensions.tile_height.to_string())
+            .attr("infinite", "0")
+            .attr("nextobjectid", &next_object_id.to_string()),
+    )?;
+    if let Some(properties) = map.properties {
+        write_properties(&mut writer, properties)?;
+    }
+
+    for tileset in &map.tilesets {
+        write_tileset(&mut writer, tileset, map_dir)?;
+    }
+
+    // Tile and object layers in the order of their map index, then in the order given
+    let groups = object_groups(&map.object_layers, &map.objects);
+    let mut layers = map
+        .layers
+        .iter()
+        .map(|layer| (layer.map_index, MapLayer::Tiles(layer)))
+        .chain(
+            groups
+                .iter()
+                .map(|group| (group.layer.map_index, MapLayer::Objects(group))),
+        )
+        .collect::<Vec<_>>();
+    layers.sort_by_key(|(map_index, _)| *map_index);
+
+    let map_height = (dimensions.height * dimensions.tile_height) as f32;
+    for (_, layer) in layers {
+        match layer {
+            MapLayer::Tiles(layer) => write_layer(&mut writer, layer, encoding)?,
+            MapLayer::Objects(group) => write_object_layer(&mut writer, group, map_height)?,
+        }
+    }
+
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Opacity and visibility attributes of a layer, left out when they are the defaults
+fn layer_attributes(opacity: f32, visible: bool) -> Vec<(&'static str, String)> {
+    let mut attributes = Vec::new();
+    if opacity != 1.0 {
+        attributes.push(("opacity", opacity.to_string()));
+    }
+    if !visible {
+        attributes.push(("visible", "0".to_owned()));
+    }
+    attributes
+}
+
+fn write_object_layer<W: Write>(
+    writer: &mut EventWriter<W>,
+    group: &ObjectGroup<'_>,
+    map_height: f32,
+) -> Result<(), TilemapError> {
+    let layer = &group.layer;
+    let mut attributes = vec![("name", layer.name.clone())];
+    if let Some(color) = &layer.color {
+        attributes.push(("color", color.clone()));
+    }
+    attributes.extend(layer_attributes(layer.opacity, layer.visible));
+    let element = attributes.iter().fold(
+        XmlEvent::start_element("objectgroup"),
+        |element, (name, value)| element.attr(*name, value),
+    );
+    writer.write(element)?;
+    write_properties(writer, &layer.properties)?;
+
+    for (object, transform) in &group.objects {
+        let translation = transform.translation();
+        // Tiled rotates clockwise, in degrees
+        let rotation = -transform.rotation().euler_angles().2.to_degrees();
+        write_object(
+            writer,
+            object,
+            translation.x,
+            map_height - translation.y,
+            rotation,
+        )?;
+    }
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_properties<W: Write>(
+    writer: &mut EventWriter<W>,
+    properties: &Properties,
+) -> Result<(), TilemapError> {
+    if properties.is_empty() {
+        return Ok(());
+    }
+
+    // Sorted so that saving the same map twice gives the same file
+    let mut names = properties.keys().collect::<Vec<_>>();
+    names.sort();
+    writer.write(XmlEvent::start_element("properties"))?;
+    for name in names {
+        let (property_type, value) = match &properties[name] {
+            Property::Bool(value) => ("bool", value.to_string()),
+            Property::Int(value) => ("int", value.to_string()),
+            Property::Float(value) => ("float", value.to_string()),
+            Property::String(value) => ("string", value.clone()),
+            Property::Color(value) => ("color", format!("#{:08x}", value)),
+            Property::File(value) => ("file", value.clone()),
+        };
+        writer.write(
+            XmlEvent::start_element("property")
+                .attr("name", name)
+                .attr("type", property_type)
+                .attr("value", &value),
+        )?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_tileset<W: Write>(
+    writer: &mut EventWriter<W>,
+    tileset: &TilesetSource<'_>,
+    map_dir: &Path,
+) -> Result<(), TilemapError> {
+    let dimensions = tileset.dimensions;
+    let data = tileset.data;
+    if let Some(source) = tileset.source {
+        writer.write(
+            XmlEvent::start_element("tileset")
+                .attr("firstgid", &dimensions.first_gid.to_string())
+                .attr("source", &relative_path(source, map_dir)),
+        )?;
+        writer.write(XmlEvent::end_element())?;
+        return Ok(());
+    }
+
+    writer.write(
+        XmlEvent::start_element("tileset")
+            .attr("firstgid", &dimensions.first_gid.to_string())
+            .attr("name", &data.name)
+            .attr("tilewidth", &dimensions.tile_width.to_string())
+            .attr("tileheight", &dimensions.tile_height.to_string())
+            .attr("spacing", &dimensions.spacing.to_string())
+            .attr("margin", &dimensions.margin.to_string())
+            .attr("tilecount", &dimensions.tile_count().to_string())
+            .attr("columns", &dimensions.width.to_string()),
+    )?;
+    write_properties(writer, &data.properties)?;
+    writer.write(
+        XmlEvent::start_element("image")
+            .attr("source", &relative_path(tileset.image_source, map_dir))
+            .attr("width", &dimensions.image_width.to_string())
+            .attr("height", &dimensions.image_height.to_string()),
+    )?;
+    writer.write(XmlEvent::end_element())?;
+
+    if !data.terrains.is_empty() {
+        writer.write(XmlEvent::start_element("terraintypes"))?;
+        for terrain in &data.terrains {
+            writer.write(
+                XmlEvent::start_element("terrain")
+                    .attr("name", &terrain.name)
+                    .attr("tile", &tile_id(terrain.tile)),
+            )?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    let mut ids = data.tiles.keys().cloned().collect::<Vec<_>>();
+    ids.sort();
+    for id in ids {
+        let tile = &data.tiles[&id];
+        let id_value = id.to_string();
+        let terrain = tile.terrain.map(|corners| {
+            corners
+                .iter()
+                .map(|corner| corner.map(|index| index.to_string()).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let mut element = XmlEvent::start_element("tile").attr("id", &id_value);
+        if let Some(tile_type) = &tile.tile_type {
+            element = element.attr("type", tile_type);
+        }
+        if let Some(terrain) = &terrain {
+            element = element.attr("terrain", terrain);
+        }
+        writer.write(element)?;
+        write_properties(writer, &tile.properties)?;
+
+        if !tile.collision.is_empty() {
+            writer.write(
+                XmlEvent::start_element("objectgroup")
+                    .attr("name", "")
+                    .attr("draworder", "index"),
+            )?;
+            for (index, shape) in tile.collision.iter().enumerate() {
+                write_tile_shape(writer, index as u32 + 1, shape)?;
+            }
+            writer.write(XmlEvent::end_element())?;
+        }
+
+        if !tile.animation.is_empty() {
+            writer.write(XmlEvent::start_element("animation"))?;
+            for frame in &tile.animation {
+                writer.write(
+                    XmlEvent::start_element("frame")
+                        .attr("tileid", &frame.tile_id.to_string())
+                        .attr("duration", &frame.duration.to_string()),
+                )?;
+                writer.write(XmlEvent::end_element())?;
+            }
+            writer.write(XmlEvent::end_element())?;
+        }
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    if !data.wang_sets.is_empty() {
+        writer.write(XmlEvent::start_element("wangsets"))?;
+        for wang_set in &data.wang_sets {
+            let wang_type = match wang_set.wang_type {
+                WangSetType::Corner => "corner",
+                WangSetType::Edge => "edge",
+                WangSetType::Mixed => "mixed",
+            };
+            writer.write(
+                XmlEvent::start_element("wangset")
+                    .attr("name", &wang_set.name)
+                    .attr("type", wang_type)
+                    .attr("tile", &tile_id(wang_set.tile)),
+            )?;
+            for color in &wang_set.colors {
+                writer.write(
+                    XmlEvent::start_element("wangcolor")
+                        .attr("name", &color.name)
+                        .attr("color", &color.color)
+                        .attr("tile", &tile_id(color.tile))
+                        .attr("probability", &color.probability.to_string()),
+                )?;
+                writer.write(XmlEvent::end_element())?;
+            }
+            let mut tiles = wang_set.tiles.iter().collect::<Vec<_>>();
+            tiles.sort_by_key(|(id, _)| **id);
+            for (id, wang_id) in tiles {
+                let wang_id = wang_id
+                    .iter()
+                    .map(|color| color.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                writer.write(
+                    XmlEvent::start_element("wangtile")
+                        .attr("tileid", &id.to_string())
+                        .attr("wangid", &wang_id),
+                )?;
+                writer.write(XmlEvent::end_element())?;
+            }
+            writer.write(XmlEvent::end_element())?;
+        }
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Path of `path` relative to the directory `base`, both relative to the same directory.
+/// Absolute paths are kept as they are.
+fn relative_path(path: &str, base: &Path) -> String {
+    let path = normalize(Path::new(path));
+    if Path::new(&path).is_absolute() {
+        return path;
+    }
+    let base = normalize(base);
+    let path = path
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    let base = base
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    let common = path
+        .iter()
+        .zip(&base)
+        .take_while(|(part, base_part)| part == base_part)
+        .count();
+    let mut relative = vec![".."; base.len() - common];
+    relative.extend_from_slice(&path[common..]);
+    relative.join("/")
+}
+
+/// Tile ids are written as -1 when there is no tile
+fn tile_id(tile: Option<u32>) -> String {
+    tile.map_or_else(|| "-1".to_owned(), |tile| tile.to_string())
+}
+
+fn write_tile_shape<W: Write>(
+    writer: &mut EventWriter<W>,
+    id: u32,
+    shape: &TileShape,
+) -> Result<(), TilemapError> {
+    let id = id.to_string();
+    let element = XmlEvent::start_element("object").attr("id", &id);
+    match shape {
+        TileShape::Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+        | TileShape::Ellipse {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (x, y, width, height) = (
+                x.to_string(),
+                y.to_string(),
+                width.to_string(),
+                height.to_string(),
+            );
+            writer.write(
+                element
+                    .attr("x", &x)
+                    .attr("y", &y)
+                    .attr("width", &width)
+                    .attr("height", &height),
+            )?;
+            if let TileShape::Ellipse { .. } = shape {
+                writer.write(XmlEvent::start_element("ellipse"))?;
+                writer.write(XmlEvent::end_element())?;
+            }
+        }
+        TileShape::Polygon(points) | TileShape::Polyline(points) => {
+            // Points are relative to the object, which sits at the top left of the tile
+            writer.write(element.attr("x", "0").attr("y", "0"))?;
+            let points = points
+                .iter()
+                .map(|point| format!("{},{}", point.x, point.y))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let name = match shape {
+                TileShape::Polygon(_) => "polygon",
+                _ => "polyline",
+            };
+            writer.write(XmlEvent::start_element(name).attr("points", &points))?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        TileShape::Point(point) => {
+            let (x, y) = (point.x.to_string(), point.y.to_string());
+            writer.write(element.attr("x", &x).attr("y", &y))?;
+            writer.write(XmlEvent::start_element("point"))?;
+            writer.write(XmlEvent::end_element())?;
+        }
+    }
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_layer<W: Write>(
+    writer: &mut EventWriter<W>,
+    layer: &TilemapLayer,
+    encoding: LayerEncoding,
+) -> Result<(), TilemapError> {
+    let mut attributes = vec![
+        ("name", layer.name.clone()),
+        ("width", layer.width.to_string()),
+        ("height", layer.height.to_string()),
+    ];
+    attributes.extend(layer_attributes(layer.opacity, layer.visible));
+    let element = attributes.iter().fold(
+        XmlEvent::start_element("layer"),
+        |element, (name, value)| element.attr(*name, value),
+    );
+    writer.write(element)?;
+    write_properties(writer, &layer.properties)?;
+
+    let gids = layer.gids();
+    match encoding {
+        LayerEncoding::Csv => {
+            writer.write(XmlEvent::start_element("data").attr("encoding", "csv"))?;
+            let rows = gids
+                .chunks(layer.width.max(1) as usize)
+                .map(|row| {
+                    row.iter()
+                        .map(|gid| gid.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+                .collect::<Vec<_>>();
+            writer.write(XmlEvent::characters(&format!("\n{}\n", rows.join(",\n"))))?;
+        }
+        LayerEncoding::Base64Zlib => {
+            writer.write(
+                XmlEvent::start_element("data")
+                    .attr("encoding", "base64")
+                    .attr("compression", "zlib"),
+            )?;
+            let mut bytes = Vec::with_capacity(gids.len() * 4);
+            for gid in gids {
+                bytes.extend_from_slice(&gid.to_le_bytes());
+            }
+            let mut encoder = libflate::zlib::Encoder::new(Vec::new())?;
+            encoder.write_all(&bytes)?;
+            let compressed = encoder.finish().into_result()?;
+            writer.write(XmlEvent::characters(&base64::encode(&compressed)))?;
+        }
+    }
+    writer.write(XmlEvent::end_element())?;
+
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_object<W: Write>(
+    writer: &mut EventWriter<W>,
+    object: &TiledObject,
+    x: f32,
+    y: f32,
+    rotation: f32,
+) -> Result<(), TilemapError> {
+    let mut attributes = vec![
+        ("id", object.id.to_string()),
+        ("name", object.name.clone()),
+        ("type", object.object_type.clone()),
+        ("x", x.to_string()),
+        ("y", y.to_string()),
+    ];
+    if object.gid != 0 {
+        attributes.push(("gid", object.gid.to_string()));
+    }
+    match object.shape {
+        ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => {
+            attributes.push(("width", width.to_string()));
+            attributes.push(("height", height.to_string()));
+        }
+        _ => {}
+    }
+    if rotation != 0.0 {
+        attributes.push(("rotation", rotation.to_string()));
+    }
+    if !object.visible {
+        attributes.push(("visible", "0".to_owned()));
+    }
+    let element = attributes.iter().fold(
+        XmlEvent::start_element("object"),
+        |element, (name, value)| element.attr(*name, value),
+    );
+    writer.write(element)?;
+    write_properties(writer, &object.properties)?;
+
+    match &object.shape {
+        ObjectShape::Ellipse { .. } => {
+            writer.write(XmlEvent::start_element("ellipse"))?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        ObjectShape::Point => {
+            writer.write(XmlEvent::start_element("point"))?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        ObjectShape::Polygon { points } | ObjectShape::Polyline { points } => {
+            // Shape points have y pointing up, Tiled's point down
+            let points = points
+                .iter()
+                .map(|point| format!("{},{}", point.x, -point.y))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let name = match object.shape {
+                ObjectShape::Polygon { .. } => "polygon",
+                _ => "polyline",
+            };
+            writer.write(XmlEvent::start_element(name).attr("points", &points))?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        ObjectShape::Rect { .. } => {}
+    }
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use amethyst::core::nalgebra::Vector2;
+
+    use super::*;
+    use crate::tilemap::{
+        convert_properties, image_paths, parse_map, AnimationFrame, Terrain, TileData, TsxCache,
+        WangColor, WangSet,
+    };
+
+    const WALLS_TSX: &[u8] = br#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.5" name="walls" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+ <image source="walls.png" width="32" height="32"/>
+</tileset>
+"#;
+
+    fn tileset_data() -> TilesetData {
+        let mut properties = Properties::new();
+        properties.insert("biome".to_owned(), Property::String("forest".to_owned()));
+        properties.insert("tint".to_owned(), Property::Color(0xff33_6699));
+
+        let mut tile_properties = Properties::new();
+        tile_properties.insert("walkable".to_owned(), Property::Bool(false));
+        tile_properties.insert("cost".to_owned(), Property::Float(2.5));
+        tile_properties.insert("script".to_owned(), Property::File("door.lua".to_owned()));
+
+        let mut tiles = HashMap::new();
+        tiles.insert(
+            1,
+            TileData {
+                tile_type: Some("wall".to_owned()),
+                properties: tile_properties,
+                animation: vec![
+                    AnimationFrame {
+                        tile_id: 1,
+                        duration: 100,
+                    },
+                    AnimationFrame {
+                        tile_id: 2,
+                        duration: 250,
+                    },
+                ],
+                terrain: Some([Some(0), Some(0), None, Some(1)]),
+                collision: vec![
+                    TileShape::Rect {
+                        x: 0.0,
+                        y: 8.0,
+                        width: 16.0,
+                        height: 8.0,
+                    },
+                    TileShape::Ellipse {
+                        x: 2.0,
+                        y: 2.0,
+                        width: 4.0,
+                        height: 4.0,
+                    },
+                    TileShape::Polygon(vec![
+                        Vector2::new(0.0, 0.0),
+                        Vector2::new(16.0, 0.0),
+                        Vector2::new(0.0, 16.0),
+                    ]),
+                    TileShape::Point(Vector2::new(8.0, 4.0)),
+                ],
+            },
+        );
+
+        let mut wang_tiles = HashMap::new();
+        wang_tiles.insert(1, [1, 0, 2, 0, 1, 0, 2, 0]);
+        TilesetData {
+            name: "terrain".to_owned(),
+            properties,
+            tiles,
+            terrains: vec![
+                Terrain {
+                    name: "Grass".to_owned(),
+                    tile: Some(3),
+                },
+                Terrain {
+                    name: "Water".to_owned(),
+                    tile: None,
+                },
+            ],
+            wang_sets: vec![WangSet {
+                name: "Paths".to_owned(),
+                tile: None,
+                wang_type: WangSetType::Edge,
+                colors: vec![
+                    WangColor {
+                        name: "Dirt".to_owned(),
+                        color: "#ff0000".to_owned(),
+                        tile: Some(2),
+                        probability: 0.5,
+                    },
+                    WangColor {
+                        name: "Stone".to_owned(),
+                        color: "#00ff00".to_owned(),
+                        tile: None,
+                        probability: 1.0,
+                    },
+                ],
+                tiles: wang_tiles,
+            }],
+        }
+    }
+
+    fn object() -> TiledObject {
+        let mut properties = Properties::new();
+        properties.insert("health".to_owned(), Property::Int(3));
+        TiledObject {
+            id: 7,
+            name: "Guard".to_owned(),
+            object_type: "enemy".to_owned(),
+            group: "actors".to_owned(),
+            map_index: 1,
+            gid: 0,
+            width: 16.0,
+            height: 24.0,
+            // Saved from the transform instead
+            rotation: 0.0,
+            visible: false,
+            shape: ObjectShape::Rect {
+                width: 16.0,
+                height: 24.0,
+            },
+            properties,
+        }
+    }
+
+    #[test]
+    fn written_maps_parse_back() {
+        let dimensions = TilemapDimensions {
+            width: 3,
+            height: 2,
+            tile_width: 16,
+            tile_height: 16,
+        };
+        let sheet_dimensions = TilesheetDimensions {
+            first_gid: 1,
+            width: 4,
+            height: 4,
+            tile_width: 16,
+            tile_height: 16,
+            margin: 1,
+            spacing: 2,
+            image_width: 71,
+            image_height: 71,
+        };
+        let data = tileset_data();
+        let mut map_properties = Properties::new();
+        map_properties.insert("music".to_owned(), Property::String("theme.ogg".to_owned()));
+
+        let mut layer_properties = Properties::new();
+        layer_properties.insert("collides".to_owned(), Property::Bool(true));
+        // Flip flags are kept in the gids
+        let gids = vec![1, 0, 2, 0x8000_0003, 16, 0];
+        let mut layers = vec![
+            TilemapLayer::new(
+                "ground",
+                3,
+                2,
+                gids.clone(),
+                vec![sheet_dimensions.clone()],
+                64,
+                layer_properties,
+            ),
+            TilemapLayer::new(
+                "overlay",
+                3,
+                2,
+                vec![0; 6],
+                vec![sheet_dimensions.clone()],
+                64,
+                Properties::new(),
+            ),
+        ];
+        // Object layers go between and above the tile layers
+        layers[1].map_index = 2;
+        layers[1].opacity = 0.5;
+        layers[1].visible = false;
+        let mut group_properties = Properties::new();
+        group_properties.insert("spawns".to_owned(), Property::Bool(true));
+        let object_layers = vec![
+            ObjectLayer {
+                name: "actors".to_owned(),
+                map_index: 1,
+                opacity: 0.75,
+                visible: false,
+                color: Some("#a0b0c0".to_owned()),
+                properties: group_properties,
+            },
+            ObjectLayer {
+                name: "triggers".to_owned(),
+                map_index: 3,
+                opacity: 1.0,
+                visible: true,
+                color: None,
+                properties: Properties::new(),
+            },
+        ];
+        let wall_dimensions = TilesheetDimensions {
+            first_gid: 17,
+            width: 2,
+            height: 2,
+            image_width: 32,
+            image_height: 32,
+            margin: 0,
+            spacing: 0,
+            ..sheet_dimensions.clone()
+        };
+        let wall_data = TilesetData::default();
+
+        let object = object();
+        let mut transform = Transform::default();
+        transform.set_x(24.0);
+        transform.set_y(20.0);
+        transform.set_rotation_euler(0.0, 0.0, -90.0f32.to_radians());
+
+        for &encoding in &[LayerEncoding::Csv, LayerEncoding::Base64Zlib] {
+            let map = MapData {
+                dimensions: &dimensions,
+                tilesets: vec![
+                    TilesetSource {
+                        dimensions: &sheet_dimensions,
+                        image_source: "tiles/terrain.png",
+                        source: None,
+                        data: &data,
+                    },
+                    TilesetSource {
+                        dimensions: &wall_dimensions,
+                        image_source: "tilesets/walls.png",
+                        source: Some("tilesets/walls.tsx"),
+                        data: &wall_data,
+                    },
+                ],
+                properties: Some(&map_properties),
+                layers: layers.iter().collect(),
+                object_layers: object_layers.iter().collect(),
+                objects: vec![(&object, &transform)],
+            };
+            let mut bytes = Vec::new();
+            write_map(&map, Path::new("maps"), encoding, &mut bytes).expect("Map is written");
+
+            let parsed = parse_map(&bytes, "maps/level.tmx", &TsxCache::default(), |path| {
+                assert_eq!(path, "tilesets/walls.tsx");
+                Ok(WALLS_TSX.to_vec())
+            })
+            .expect("Written map parses");
+            let map = &parsed.map;
+            assert_eq!(map.version, TMX_VERSION);
+            assert_eq!((map.width, map.height), (3, 2));
+            assert_eq!((map.tile_width, map.tile_height), (16, 16));
+            assert_eq!(convert_properties(&map.properties), map_properties);
+
+            // Tile layers, in their place among the object layers
+            assert_eq!(map.layers.len(), 2);
+            assert_eq!(parsed.layer_indices, vec![0, 2]);
+            assert_eq!(map.layers[0].name, "ground");
+            let parsed_gids = map.layers[0]
+                .tiles
+                .iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(parsed_gids, gids);
+            assert_eq!(
+                convert_properties(&map.layers[0].properties),
+                layers[0].properties
+            );
+            assert_eq!((map.layers[0].opacity, map.layers[0].visible), (1.0, true));
+            assert_eq!(map.layers[1].name, "overlay");
+            assert_eq!((map.layers[1].opacity, map.layers[1].visible), (0.5, false));
+
+            // Embedded tileset, with its image relative to the map
+            assert_eq!(map.tilesets.len(), 2);
+            let tileset = &map.tilesets[0];
+            assert_eq!(tileset.first_gid, 1);
+            assert_eq!((tileset.margin, tileset.spacing), (1, 2));
+            assert_eq!(tileset.images[0].source, "../tiles/terrain.png");
+            assert_eq!(
+                (tileset.images[0].width, tileset.images[0].height),
+                (71, 71)
+            );
+
+            let parsed_data = &parsed.tilesets[0];
+            assert_eq!(parsed_data.name, data.name);
+            assert_eq!(parsed_data.properties, data.properties);
+            assert_eq!(parsed_data.terrains, data.terrains);
+            assert_eq!(parsed_data.wang_sets, data.wang_sets);
+            assert_eq!(parsed_data.tiles.len(), 1);
+            let (tile, parsed_tile) = (&data.tiles[&1], &parsed_data.tiles[&1]);
+            assert_eq!(parsed_tile.tile_type, tile.tile_type);
+            assert_eq!(parsed_tile.properties, tile.properties);
+            assert_eq!(parsed_tile.animation, tile.animation);
+            assert_eq!(parsed_tile.terrain, tile.terrain);
+            assert_eq!(parsed_tile.collision, tile.collision);
+
+            // External tileset, still referenced
+            assert_eq!(
+                parsed.tileset_sources,
+                vec![None, Some("../tilesets/walls.tsx".to_owned())]
+            );
+            assert_eq!(map.tilesets[1].first_gid, 17);
+            assert_eq!(map.tilesets[1].images[0].source, "../tilesets/walls.png");
+            assert_eq!(parsed.tilesets[1].name, "walls");
+
+            // Object layers, the empty one included
+            assert_eq!(map.object_groups.len(), 2);
+            let group = &map.object_groups[0];
+            assert_eq!(group.name, "actors");
+            assert_eq!((group.opacity, group.visible), (0.75, false));
+            assert_eq!(parsed.object_groups[0].map_index, 1);
+            assert_eq!(parsed.object_groups[0].color, object_layers[0].color);
+            assert_eq!(
+                parsed.object_groups[0].properties,
+                object_layers[0].properties
+            );
+            assert_eq!(map.object_groups[1].name, "triggers");
+            assert!(map.object_groups[1].objects.is_empty());
+            assert_eq!(parsed.object_groups[1].map_index, 3);
+
+            // Objects, placed and rotated by their transform
+            let parsed_object = &group.objects[0];
+            assert_eq!(parsed_object.id, 7);
+            assert_eq!(parsed_object.name, "Guard");
+            assert_eq!(parsed_object.obj_type, "enemy");
+            assert_eq!((parsed_object.x, parsed_object.y), (24.0, 12.0));
+            assert_eq!(
+                convert_properties(&parsed_object.properties),
+                object.properties
+            );
+            let attributes = &parsed.objects[0];
+            assert_eq!((attributes.width, attributes.height), (16.0, 24.0));
+            assert!((attributes.rotation - 90.0).abs() < 1e-3);
+            assert!(!attributes.visible);
+            assert!(!attributes.point);
+        }
+    }
+
+    #[test]
+    fn objects_without_their_layer_are_grouped_by_name() {
+        let mut stray = object();
+        stray.group = "props".to_owned();
+        stray.map_index = 5;
+        let transform = Transform::default();
+        let actors = object();
+        let layer = ObjectLayer {
+            name: "actors".to_owned(),
+            map_index: 1,
+            opacity: 1.0,
+            visible: true,
+            color: None,
+            properties: Properties::new(),
+        };
+        let groups = object_groups(
+            &[&layer],
+            &[
+                (&actors, &transform),
+                (&stray, &transform),
+                (&stray, &transform),
+            ],
+        );
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].layer.name, "actors");
+        assert_eq!(groups[0].objects.len(), 1);
+        assert_eq!(groups[1].layer.name, "props");
+        assert_eq!(groups[1].layer.map_index, 5);
+        assert_eq!(groups[1].objects.len(), 2);
+    }
+
+    #[test]
+    fn image_paths_are_relative_to_the_map() {
+        assert_eq!(
+            relative_path("tiles/terrain.png", Path::new("maps")),
+            "../tiles/terrain.png"
+        );
+        assert_eq!(
+            relative_path("maps/tiles/terrain.png", Path::new("maps")),
+            "tiles/terrain.png"
+        );
+        assert_eq!(
+            relative_path("terrain.png", Path::new("maps/dungeon")),
+            "../../terrain.png"
+        );
+        assert_eq!(
+            relative_path("maps/./../terrain.png", Path::new("")),
+            "terrain.png"
+        );
+        assert_eq!(
+            relative_path("/assets/terrain.png", Path::new("maps")),
+            "/assets/terrain.png"
+        );
+    }
+
+    #[test]
+    fn loaded_image_paths_are_written_back() {
+        // initialise_tilemap spawns maps with their base directory joined to their directory
+        let map_dir = Path::new("/home/player/game/resources").join("maps");
+        let (load_path, image_source) = image_paths(&map_dir, "../tiles/./terrain.png");
+        assert_eq!(
+            load_path,
+            Path::new("/home/player/game/resources/maps/../tiles/./terrain.png")
+        );
+        assert_eq!(image_source, "../tiles/terrain.png");
+        assert_eq!(
+            relative_path(&image_source, Path::new("")),
+            "../tiles/terrain.png"
+        );
+        assert_eq!(
+            relative_path(&image_source, Path::new("saves")),
+            "../../tiles/terrain.png"
+        );
+    }
+
+    #[test]
+    fn writing_without_layers_fails() {
+        let mut world = World::new();
+        world.register::<TilemapLayer>();
+        world.register::<TilemapDimensions>();
+        world.register::<Tilesheets>();
+        world.register::<MapProperties>();
+        world.register::<TiledObject>();
+        world.register::<ObjectLayer>();
+        world.register::<Transform>();
+        match write_tmx(
+            &world,
+            &[],
+            &[],
+            Path::new(""),
+            LayerEncoding::Csv,
+            Vec::new(),
+        ) {
+            Err(TilemapError::NoLayers) => {}
+            result => panic!("Expected NoLayers, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,544 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use amethyst::core::nalgebra::Vector2;
+use tiled::{parse, parse_tileset};
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+use xml::writer::{self, EmitterConfig};
+
+use log::debug;
+
+use super::property::FILE_PROPERTY_MARKER;
+use super::terrain::{parse_tile_terrain, TerrainReader};
+use super::{
+    AnimationFrame, ObjectAttributes, ObjectGroupAttributes, Properties, Property, TileData,
+    TileShape, TilemapError, TilesetData,
+};
+
+/// Parsed external .tsx tilesets, keyed by their path relative to the asset directory.
+/// Cloning a `TsxCache` gives another handle to the same cache, so it can be shared between
+/// every map that is loaded.
+#[derive(Clone, Default)]
+pub struct TsxCache {
+    tilesets: Arc<Mutex<HashMap<String, (tiled::Tileset, Arc<TilesetData>)>>>,
+}
+
+impl TsxCache {
+    /// Returns the tileset at `path` and its data, parsing the bytes returned by `load` if it
+    /// is not cached yet. The returned tileset starts at `first_gid`.
+    pub fn get_or_load<F>(
+        &self,
+        path: &str,
+        first_gid: u32,
+        load: F,
+    ) -> Result<(tiled::Tileset, Arc<TilesetData>), TilemapError>
+    where
+        F: FnOnce(&str) -> Result<Vec<u8>, TilemapError>,
+    {
+        let mut tilesets = self.tilesets.lock().expect("TsxCache lock poisoned");
+        if !tilesets.contains_key(path) {
+            debug!("Loading external tileset {}", path);
+            let tsx = preprocess(&load(path)?)?;
+            let tileset = parse_tileset(&tsx.bytes[..], 1)?;
+            let data = tsx.tilesets.into_iter().next().unwrap_or_default();
+            tilesets.insert(path.to_owned(), (tileset, Arc::new(data)));
+        }
+
+        let (tileset, data) = &tilesets[path];
+        let mut tileset = tileset.clone();
+        tileset.first_gid = first_gid;
+        Ok((tileset, Arc::clone(data)))
+    }
+
+    /// Forgets every cached tileset, so changed .tsx files are read again
+    pub fn clear(&self) {
+        self.tilesets
+            .lock()
+            .expect("TsxCache lock poisoned")
+            .clear();
+    }
+}
+
+/// A map parsed by `parse_map`: what `tiled` reads of it, and what this crate reads itself
+#[derive(Clone, Debug)]
+pub struct TiledMap {
+    pub map: tiled::Map,
+    /// Data of each tileset of `map`, in the same order as `map.tilesets`
+    pub tilesets: Vec<Arc<TilesetData>>,
+    /// Path of the .tsx file of each tileset of `map` relative to the map, `None` for the
+    /// tilesets embedded in it
+    pub tileset_sources: Vec<Option<String>>,
+    /// Position of each layer of `map.layers` among the tile and object layers of the map
+    pub layer_indices: Vec<usize>,
+    /// Attributes of the object layers of `map.object_groups`, in the same order
+    pub object_groups: Vec<ObjectGroupAttributes>,
+    /// Attributes of the objects of `map.object_groups`, in the order of their groups and of
+    /// the objects in each group
+    pub objects: Vec<ObjectAttributes>,
+}
+
+/// A `<tileset firstgid=".." source=".."/>` element of a map
+struct ExternalTileset {
+    first_gid: u32,
+    source: String,
+}
+
+/// Parses a .tmx map, resolving its external tilesets through `cache`.
+/// `map_path` is the path of the map relative to the asset directory, and `load` reads a file
+/// given its path relative to the same directory.
+pub fn parse_map<F>(
+    bytes: &[u8],
+    map_path: &str,
+    cache: &TsxCache,
+    mut load: F,
+) -> Result<TiledMap, TilemapError>
+where
+    F: FnMut(&str) -> Result<Vec<u8>, TilemapError>,
+{
+    let tmx = preprocess(bytes)?;
+    let mut map = parse(&tmx.bytes[..])?;
+
+    // Both list the embedded tilesets in the order they are in the map
+    let mut tilesets = map
+        .tilesets
+        .drain(..)
+        .zip(tmx.tilesets.into_iter().map(Arc::new))
+        .map(|(tileset, data)| (tileset, data, None))
+        .collect::<Vec<_>>();
+
+    let map_dir = Path::new(map_path).parent().unwrap_or(Path::new(""));
+    for external in tmx.external_tilesets {
+        let tsx_path = map_dir.join(&external.source);
+        let (mut tileset, data) =
+            cache.get_or_load(&normalize(&tsx_path), external.first_gid, &mut load)?;
+
+        // Images of a .tsx are relative to it, but tilesheets are loaded relative to the map.
+        let tsx_dir = Path::new(&external.source)
+            .parent()
+            .unwrap_or(Path::new(""));
+        for image in tileset.images.iter_mut() {
+            image.source = normalize(&tsx_dir.join(&image.source));
+        }
+        for tile in tileset.tiles.iter_mut() {
+            for image in tile.images.iter_mut() {
+                image.source = normalize(&tsx_dir.join(&image.source));
+            }
+        }
+
+        tilesets.push((tileset, data, Some(normalize(Path::new(&external.source)))));
+    }
+    tilesets.sort_by_key(|(tileset, _, _)| tileset.first_gid);
+    let mut data = Vec::with_capacity(tilesets.len());
+    let mut tileset_sources = Vec::with_capacity(tilesets.len());
+    for (tileset, tileset_data, source) in tilesets {
+        map.tilesets.push(tileset);
+        data.push(tileset_data);
+        tileset_sources.push(source);
+    }
+
+    Ok(TiledMap {
+        map,
+        tilesets: data,
+        tileset_sources,
+        layer_indices: tmx.layer_indices,
+        object_groups: tmx.object_groups,
+        objects: tmx.objects,
+    })
+}
+
+/// A map or tileset after `preprocess`
+struct Preprocessed {
+    /// The file rewritten so that `tiled` can parse it
+    bytes: Vec<u8>,
+    external_tilesets: Vec<ExternalTileset>,
+    /// Data of the embedded tilesets, or of the tileset of a .tsx file, in document order
+    tilesets: Vec<TilesetData>,
+    /// Position of each tile layer of the map among its tile and object layers
+    layer_indices: Vec<usize>,
+    /// Attributes of the map's object layers, in document order
+    object_groups: Vec<ObjectGroupAttributes>,
+    /// Attributes of the objects of the map's object layers, in document order
+    objects: Vec<ObjectAttributes>,
+}
+
+/// Rewrites a map or tileset into something `tiled` can parse on its own, and reads what it
+/// doesn't parse along the way.
+/// External tileset elements, which it can't resolve without touching the file system, are
+/// removed and returned, and file properties, which it doesn't know, are turned into marked
+/// string properties.
+fn preprocess(bytes: &[u8]) -> Result<Preprocessed, TilemapError> {
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut external_tilesets = Vec::new();
+    let mut data = DataReader::default();
+    {
+        let mut writer = EmitterConfig::new()
+            .perform_indent(false)
+            .create_writer(&mut output);
+        // Depth of the element being skipped, 0 when not inside an external tileset
+        let mut skip_depth = 0;
+        // Names of the elements enclosing the current event
+        let mut path: Vec<String> = Vec::new();
+
+        for event in EventReader::new(bytes) {
+            let event = event?;
+            if skip_depth > 0 {
+                match event {
+                    XmlEvent::StartElement { .. } => skip_depth += 1,
+                    XmlEvent::EndElement { .. } => skip_depth -= 1,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match event {
+                XmlEvent::StartElement {
+                    ref name,
+                    ref attributes,
+                    ..
+                } => {
+                    if name.local_name == "tileset" {
+                        if let Some(source) = attribute(attributes, "source") {
+                            external_tilesets.push(ExternalTileset {
+                                first_gid: attribute(attributes, "firstgid")
+                                    .and_then(|first_gid| first_gid.parse().ok())
+                                    .unwrap_or(1),
+                                source: source.to_owned(),
+                            });
+                            skip_depth = 1;
+                            continue;
+                        }
+                    }
+
+                    data.start(&path, &name.local_name, attributes);
+                    path.push(name.local_name.clone());
+                }
+                XmlEvent::EndElement { ref name } => {
+                    path.pop();
+                    data.end(&path, &name.local_name);
+                }
+                XmlEvent::Characters(ref text) => data.characters(&path, text),
+                _ => {}
+            }
+
+            match event {
+                XmlEvent::StartElement {
+                    ref name,
+                    ref attributes,
+                    ref namespace,
+                } if name.local_name == "property"
+                    && attributes.iter().any(|attribute| {
+                        attribute.name.local_name == "type" && attribute.value == "file"
+                    }) =>
+                {
+                    let attributes = attributes
+                        .iter()
+                        .map(|attribute| match attribute.name.local_name.as_str() {
+                            "type" => OwnedAttribute::new(attribute.name.clone(), "string"),
+                            "value" => OwnedAttribute::new(
+                                attribute.name.clone(),
+                                format!("{}{}", FILE_PROPERTY_MARKER, attribute.value),
+                            ),
+                            _ => attribute.clone(),
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write(writer::XmlEvent::StartElement {
+                        name: name.borrow(),
+                        attributes: attributes.iter().map(|a| a.borrow()).collect(),
+                        namespace: Cow::Borrowed(namespace),
+                    })?;
+                }
+                _ => {
+                    if let Some(event) = event.as_writer_event() {
+                        writer.write(event)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(Preprocessed {
+        bytes: output,
+        external_tilesets,
+        tilesets: data.tilesets,
+        layer_indices: data.layer_indices,
+        object_groups: data.object_groups,
+        objects: data.objects,
+    })
+}
+
+/// Element a `<property>` belongs to
+enum PropertyOwner {
+    Tileset,
+    Tile,
+    ObjectGroup,
+}
+
+/// A `<property>` element whose end hasn't been reached yet
+struct PendingProperty {
+    owner: PropertyOwner,
+    name: String,
+    property_type: Option<String>,
+    /// Taken from the text of the element when it has no `value` attribute, as Tiled does
+    /// for multiline strings
+    value: Option<String>,
+}
+
+/// Reads the data of tilesets out of the events of a map or tileset: their properties,
+/// terrains and Wang sets, and the types, properties, terrains, collision shapes and
+/// animations of their tiles.
+/// Also reads the order of the layers of a map, and the attributes of its object layers and
+/// objects that `tiled` drops.
+#[derive(Default)]
+struct DataReader {
+    tilesets: Vec<TilesetData>,
+    /// Number of tile and object layers of the map read so far
+    layer_count: usize,
+    layer_indices: Vec<usize>,
+    object_groups: Vec<ObjectGroupAttributes>,
+    objects: Vec<ObjectAttributes>,
+    /// Terrain types and Wang sets of the last tileset, added to it at its end
+    terrain_sets: TerrainReader,
+    /// Tile being read and its id, added to the last tileset at its end
+    tile: Option<(u32, TileData)>,
+    /// Collision shape being read, added to the tile at its end
+    shape: Option<TileShape>,
+    property: Option<PendingProperty>,
+}
+
+impl DataReader {
+    /// Reads a start element, `path` holding the names of the elements enclosing it
+    fn start(&mut self, path: &[String], name: &str, attributes: &[OwnedAttribute]) {
+        if in_terrain_sets(path) {
+            self.terrain_sets.start(name, attributes);
+            return;
+        }
+
+        let value = |key: &str| attribute(attributes, key);
+        match (path.last().map(String::as_str), name) {
+            (Some("map"), "layer") if path.len() == 1 => {
+                self.layer_indices.push(self.layer_count);
+                self.layer_count += 1;
+            }
+            (Some("map"), "objectgroup") if path.len() == 1 => {
+                self.object_groups.push(ObjectGroupAttributes {
+                    map_index: self.layer_count,
+                    properties: Properties::new(),
+                    color: value("color").map(str::to_owned),
+                });
+                self.layer_count += 1;
+            }
+            (_, "tileset") => self.tilesets.push(TilesetData {
+                name: value("name").unwrap_or("").to_owned(),
+                ..TilesetData::default()
+            }),
+            (Some("tileset"), "tile") => {
+                self.tile = value("id").and_then(|id| id.parse().ok()).map(|id| {
+                    let tile = TileData {
+                        // Renamed to class in Tiled 1.9
+                        tile_type: value("type").or_else(|| value("class")).map(str::to_owned),
+                        terrain: value("terrain").and_then(parse_tile_terrain),
+                        ..TileData::default()
+                    };
+                    (id, tile)
+                });
+            }
+            (Some("animation"), "frame") => {
+                let number = |key: &str| value(key).and_then(|v| v.parse().ok()).unwrap_or(0);
+                if let Some((_, tile)) = self.tile.as_mut() {
+                    tile.animation.push(AnimationFrame {
+                        tile_id: number("tileid"),
+                        duration: number("duration"),
+                    });
+                }
+            }
+            (Some("objectgroup"), "object") if is_map_object_group(path) => {
+                let number = |key: &str| value(key).and_then(|v| v.parse().ok()).unwrap_or(0.0);
+                self.objects.push(ObjectAttributes {
+                    width: number("width"),
+                    height: number("height"),
+                    rotation: number("rotation"),
+                    visible: value("visible").map_or(true, |v| v != "0" && v != "false"),
+                    point: false,
+                });
+            }
+            (Some("object"), "point") if is_map_object_group(&path[..path.len() - 1]) => {
+                if let Some(object) = self.objects.last_mut() {
+                    object.point = true;
+                }
+            }
+            (Some("objectgroup"), "object") if self.tile.is_some() => {
+                let number = |key: &str| value(key).and_then(|v| v.parse().ok()).unwrap_or(0.0);
+                self.shape = Some(TileShape::Rect {
+                    x: number("x"),
+                    y: number("y"),
+                    width: number("width"),
+                    height: number("height"),
+                });
+            }
+            (Some("object"), kind) => {
+                if let Some(TileShape::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                }) = self.shape
+                {
+                    let points = || parse_points(value("points").unwrap_or(""), x, y);
+                    self.shape = Some(match kind {
+                        "ellipse" => TileShape::Ellipse {
+                            x,
+                            y,
+                            width,
+                            height,
+                        },
+                        "point" => TileShape::Point(Vector2::new(x, y)),
+                        "polygon" => TileShape::Polygon(points()),
+                        "polyline" => TileShape::Polyline(points()),
+                        _ => return,
+                    });
+                }
+            }
+            (Some("properties"), "property") => {
+                let owner = match path.iter().rev().nth(1).map(String::as_str) {
+                    Some("tileset") => PropertyOwner::Tileset,
+                    Some("tile") => PropertyOwner::Tile,
+                    Some("objectgroup") if is_map_object_group(&path[..path.len() - 1]) => {
+                        PropertyOwner::ObjectGroup
+                    }
+                    _ => return,
+                };
+                self.property = Some(PendingProperty {
+                    owner,
+                    name: value("name").unwrap_or("").to_owned(),
+                    property_type: value("type").map(str::to_owned),
+                    value: value("value").map(str::to_owned),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads the text inside an element
+    fn characters(&mut self, path: &[String], text: &str) {
+        if path.last().map(String::as_str) == Some("property") {
+            if let Some(property) = self.property.as_mut() {
+                property.value.get_or_insert_with(|| text.to_owned());
+            }
+        }
+    }
+
+    /// Reads an end element, `path` holding the names of the elements enclosing it
+    fn end(&mut self, path: &[String], name: &str) {
+        if in_terrain_sets(path) {
+            self.terrain_sets.end(name);
+            return;
+        }
+
+        match (path.last().map(String::as_str), name) {
+            (Some("properties"), "property") => {
+                let pending = match self.property.take() {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                let property = match Property::parse(
+                    pending.property_type.as_ref().map(String::as_str),
+                    pending.value.as_ref().map_or("", String::as_str),
+                ) {
+                    Some(property) => property,
+                    None => return,
+                };
+                let properties = match pending.owner {
+                    PropertyOwner::Tileset => self
+                        .tilesets
+                        .last_mut()
+                        .map(|tileset| &mut tileset.properties),
+                    PropertyOwner::Tile => self.tile.as_mut().map(|(_, tile)| &mut tile.properties),
+                    PropertyOwner::ObjectGroup => self
+                        .object_groups
+                        .last_mut()
+                        .map(|group| &mut group.properties),
+                };
+                if let Some(properties) = properties {
+                    properties.insert(pending.name, property);
+                }
+            }
+            (Some("objectgroup"), "object") => {
+                if let (Some(shape), Some((_, tile))) = (self.shape.take(), self.tile.as_mut()) {
+                    tile.collision.push(shape);
+                }
+            }
+            (Some("tileset"), "tile") => {
+                if let (Some((id, tile)), Some(tileset)) =
+                    (self.tile.take(), self.tilesets.last_mut())
+                {
+                    tileset.tiles.insert(id, tile);
+                }
+            }
+            (_, "tileset") => {
+                let terrain_sets = mem::replace(&mut self.terrain_sets, TerrainReader::default());
+                if let Some(tileset) = self.tilesets.last_mut() {
+                    let (terrains, wang_sets) = terrain_sets.finish();
+                    tileset.terrains = terrains;
+                    tileset.wang_sets = wang_sets;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether an element is inside the `<terraintypes>` or `<wangsets>` of a tileset, given the
+/// names of the elements enclosing it
+fn in_terrain_sets(path: &[String]) -> bool {
+    path.iter()
+        .any(|name| name == "terraintypes" || name == "wangsets")
+}
+
+/// Whether an element is directly inside an object layer of a map, given the names of the
+/// elements enclosing it.
+/// `tiled` only reads the object layers at the top of the map, not those in group layers.
+fn is_map_object_group(path: &[String]) -> bool {
+    path.len() == 2 && path[0] == "map" && path[1] == "objectgroup"
+}
+
+/// Parses the `points` attribute of a polygon or polyline, whose points are relative to the
+/// position of their object
+fn parse_points(value: &str, x: f32, y: f32) -> Vec<Vector2<f32>> {
+    value
+        .split_whitespace()
+        .filter_map(|point| {
+            let mut coordinates = point.split(',');
+            let point_x: f32 = coordinates.next()?.parse().ok()?;
+            let point_y: f32 = coordinates.next()?.parse().ok()?;
+            Some(Vector2::new(x + point_x, y + point_y))
+        })
+        .collect()
+}
+
+pub(crate) fn attribute<'a>(attributes: &'a [OwnedAttribute], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|attribute| attribute.name.local_name == key)
+        .map(|attribute| attribute.value.as_str())
+}
+
+/// Resolves `.` and `..` in a path and uses `/` as separator
+pub(crate) fn normalize(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized.to_string_lossy().replace('\\', "/")
+}
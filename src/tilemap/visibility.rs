@@ -0,0 +1,417 @@
+use std::collections::HashSet;
+
+use amethyst::core::nalgebra::Vector2;
+use amethyst::ecs::{Component, DenseVecStorage};
+
+use super::{TilemapLayer, Tilesheets};
+
+/// Cells seen from a point
+pub type VisibleTiles = HashSet<(u32, u32)>;
+
+/// Whether the cells of a layer block sight, read from a bool property of their tiles.
+/// Empty cells and tiles without the property don't.
+pub fn layer_opacity<'a>(
+    layer: &'a TilemapLayer,
+    tilesheets: &'a Tilesheets,
+    property: &'a str,
+) -> impl Fn(u32, u32) -> bool + 'a {
+    move |x, y| {
+        layer
+            .tile_property(tilesheets, x, y, property)
+            .and_then(|property| property.as_bool())
+            .unwrap_or(false)
+    }
+}
+
+/// Cells on the Bresenham line between two cells, both included
+pub fn line(from: (u32, u32), to: (u32, u32)) -> Vec<(u32, u32)> {
+    let (mut x, mut y) = (i64::from(from.0), i64::from(from.1));
+    let (to_x, to_y) = (i64::from(to.0), i64::from(to.1));
+    let dx = (to_x - x).abs();
+    let dy = -(to_y - y).abs();
+    let step_x = if x < to_x { 1 } else { -1 };
+    let step_y = if y < to_y { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut cells = Vec::with_capacity((dx - dy + 1) as usize);
+    loop {
+        cells.push((x as u32, y as u32));
+        if x == to_x && y == to_y {
+            return cells;
+        }
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/// Whether `to` can be seen from `from`: no cell strictly between them on their Bresenham
+/// line is opaque
+pub fn line_of_sight<F>(from: (u32, u32), to: (u32, u32), opaque: F) -> bool
+where
+    F: Fn(u32, u32) -> bool,
+{
+    let cells = line(from, to);
+    cells.len() <= 2
+        || cells[1..cells.len() - 1]
+            .iter()
+            .all(|&(x, y)| !opaque(x, y))
+}
+
+/// First opaque cell hit by a ray
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    pub x: u32,
+    pub y: u32,
+    /// Distance from the origin of the ray to where it enters the cell, in tiles
+    pub distance: f32,
+}
+
+/// Walks a ray through the grid cell by cell (DDA) until it enters an opaque cell, leaves the
+/// `width` x `height` grid or travels `max_distance`.
+/// `origin` is in tiles from the top left corner of the grid, with y pointing down like tile
+/// coordinates, and `direction` doesn't need to be normalized.
+pub fn cast_ray<F>(
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    max_distance: f32,
+    (width, height): (u32, u32),
+    opaque: F,
+) -> Option<RayHit>
+where
+    F: Fn(u32, u32) -> bool,
+{
+    let direction = direction.try_normalize(std::f32::EPSILON)?;
+    let mut cell = (origin.x.floor() as i64, origin.y.floor() as i64);
+    let step = (
+        if direction.x < 0.0 { -1 } else { 1 },
+        if direction.y < 0.0 { -1 } else { 1 },
+    );
+    // Distance along the ray between two vertical and two horizontal grid lines
+    let delta = Vector2::new((1.0 / direction.x).abs(), (1.0 / direction.y).abs());
+    // Distance along the ray to the next vertical and horizontal grid lines
+    let mut next = Vector2::new(
+        if direction.x < 0.0 {
+            (origin.x - cell.0 as f32) * delta.x
+        } else {
+            (cell.0 as f32 + 1.0 - origin.x) * delta.x
+        },
+        if direction.y < 0.0 {
+            (origin.y - cell.1 as f32) * delta.y
+        } else {
+            (cell.1 as f32 + 1.0 - origin.y) * delta.y
+        },
+    );
+
+    let mut distance = 0.0;
+    while distance <= max_distance {
+        if cell.0 < 0 || cell.1 < 0 || cell.0 >= i64::from(width) || cell.1 >= i64::from(height) {
+            return None;
+        }
+        if opaque(cell.0 as u32, cell.1 as u32) {
+            return Some(RayHit {
+                x: cell.0 as u32,
+                y: cell.1 as u32,
+                distance,
+            });
+        }
+
+        if next.x < next.y {
+            distance = next.x;
+            next.x += delta.x;
+            cell.0 += step.0;
+        } else {
+            distance = next.y;
+            next.y += delta.y;
+            cell.1 += step.1;
+        }
+    }
+    None
+}
+
+/// Cells visible from `origin` within `radius` tiles, computed with symmetric shadowcasting:
+/// a cell sees another exactly when the other sees it. Opaque cells are visible, but hide
+/// the cells behind them.
+pub fn field_of_view<F>(
+    origin: (u32, u32),
+    radius: Option<u32>,
+    (width, height): (u32, u32),
+    opaque: F,
+) -> VisibleTiles
+where
+    F: Fn(u32, u32) -> bool,
+{
+    let mut visible = VisibleTiles::new();
+    if origin.0 >= width || origin.1 >= height {
+        return visible;
+    }
+    visible.insert(origin);
+
+    let origin = (i64::from(origin.0), i64::from(origin.1));
+    let inside = |x: i64, y: i64| x >= 0 && y >= 0 && x < i64::from(width) && y < i64::from(height);
+    let in_range = |depth: i64, column: i64| {
+        radius.map_or(true, |radius| {
+            depth * depth + column * column <= i64::from(radius) * i64::from(radius)
+        })
+    };
+
+    for quadrant in 0..4 {
+        // Cell at `depth` rows away from the origin and `column` columns to its side
+        let cell = |depth: i64, column: i64| match quadrant {
+            0 => (origin.0 + column, origin.1 - depth),
+            1 => (origin.0 + depth, origin.1 + column),
+            2 => (origin.0 + column, origin.1 + depth),
+            _ => (origin.0 - depth, origin.1 + column),
+        };
+        // Cells outside the grid are walls that can't be seen
+        let wall = |depth: i64, column: i64| {
+            let (x, y) = cell(depth, column);
+            !inside(x, y) || opaque(x as u32, y as u32)
+        };
+
+        let mut rows = vec![Row {
+            depth: 1,
+            start_slope: -1.0,
+            end_slope: 1.0,
+        }];
+        while let Some(mut row) = rows.pop() {
+            if radius.map_or(false, |radius| row.depth > i64::from(radius)) {
+                continue;
+            }
+
+            let mut previous_wall = None;
+            let min_column = (row.depth as f32 * row.start_slope + 0.5).floor() as i64;
+            let max_column = (row.depth as f32 * row.end_slope - 0.5).ceil() as i64;
+            for column in min_column..=max_column {
+                let is_wall = wall(row.depth, column);
+                let (x, y) = cell(row.depth, column);
+                if inside(x, y)
+                    && in_range(row.depth, column)
+                    && (is_wall || row.is_symmetric(column))
+                {
+                    visible.insert((x as u32, y as u32));
+                }
+
+                if previous_wall == Some(true) && !is_wall {
+                    row.start_slope = slope(row.depth, column);
+                }
+                if previous_wall == Some(false) && is_wall {
+                    let mut next = row.next();
+                    next.end_slope = slope(row.depth, column);
+                    rows.push(next);
+                }
+                previous_wall = Some(is_wall);
+            }
+            if previous_wall == Some(false) {
+                rows.push(row.next());
+            }
+        }
+    }
+    visible
+}
+
+/// A row of a quadrant scanned by `field_of_view`, between two slopes
+#[derive(Clone, Copy)]
+struct Row {
+    depth: i64,
+    start_slope: f32,
+    end_slope: f32,
+}
+
+impl Row {
+    fn next(&self) -> Row {
+        Row {
+            depth: self.depth + 1,
+            ..*self
+        }
+    }
+
+    /// Whether the centre of a cell of the row lies between the slopes
+    fn is_symmetric(&self, column: i64) -> bool {
+        let column = column as f32;
+        let depth = self.depth as f32;
+        column >= depth * self.start_slope && column <= depth * self.end_slope
+    }
+}
+
+/// Slope of the left edge of a cell, as seen from the origin
+fn slope(depth: i64, column: i64) -> f32 {
+    (2 * column - 1) as f32 / (2 * depth) as f32
+}
+
+/// What is known about a cell under fog of war
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fog {
+    /// Seen right now
+    Visible = 0,
+    /// Seen before, but not anymore
+    Remembered = 1,
+    /// Never seen
+    Hidden = 2,
+}
+
+/// Fog of war over a layer. `DrawTilemap` darkens remembered and hidden cells of layers that
+/// have one.
+#[derive(Clone, Debug)]
+pub struct FogOfWar {
+    width: u32,
+    height: u32,
+    cells: Vec<Fog>,
+    /// Incremented whenever a cell changes, so the layer is uploaded again
+    revision: u64,
+    /// Brightness of remembered cells, from 0 for black to 1 for unchanged
+    pub remembered_brightness: f32,
+    /// Brightness of hidden cells
+    pub hidden_brightness: f32,
+}
+
+impl FogOfWar {
+    /// Fog over a `width` x `height` layer that hides every cell
+    pub fn new(width: u32, height: u32) -> Self {
+        FogOfWar {
+            width,
+            height,
+            cells: vec![Fog::Hidden; (width * height) as usize],
+            revision: 0,
+            remembered_brightness: 0.5,
+            hidden_brightness: 0.0,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Option<Fog> {
+        if x < self.width && y < self.height {
+            Some(self.cells[(y * self.width + x) as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Makes the given cells visible. Cells that were visible before and aren't in `visible`
+    /// become remembered.
+    /// The revision only changes when some cell does.
+    pub fn reveal(&mut self, visible: &VisibleTiles) {
+        let mut changed = false;
+        let width = self.width;
+        for (index, fog) in self.cells.iter_mut().enumerate() {
+            let cell = (index as u32 % width, index as u32 / width);
+            let revealed = if visible.contains(&cell) {
+                Fog::Visible
+            } else if *fog == Fog::Visible {
+                Fog::Remembered
+            } else {
+                *fog
+            };
+            if revealed != *fog {
+                *fog = revealed;
+                changed = true;
+            }
+        }
+        if changed {
+            self.revision += 1;
+        }
+    }
+
+    /// Hides every cell again
+    pub fn reset(&mut self) {
+        for fog in self.cells.iter_mut() {
+            *fog = Fog::Hidden;
+        }
+        self.revision += 1;
+    }
+
+    /// Counter incremented whenever a cell changes
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+impl Component for FogOfWar {
+    type Storage = DenseVecStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: [&str; 7] = [
+        "..........",
+        "..#....#..",
+        "..#..#....",
+        "......##..",
+        ".#........",
+        "....#...#.",
+        "..........",
+    ];
+
+    fn opaque(x: u32, y: u32) -> bool {
+        MAP[y as usize].as_bytes()[x as usize] == b'#'
+    }
+
+    fn floor() -> Vec<(u32, u32)> {
+        let mut cells = Vec::new();
+        for y in 0..MAP.len() as u32 {
+            for x in 0..MAP[0].len() as u32 {
+                if !opaque(x, y) {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn field_of_view_is_symmetric() {
+        let size = (MAP[0].len() as u32, MAP.len() as u32);
+        let cells = floor();
+        let views = cells
+            .iter()
+            .map(|&cell| field_of_view(cell, None, size, opaque))
+            .collect::<Vec<_>>();
+        for (a, view_a) in cells.iter().zip(&views) {
+            for (b, view_b) in cells.iter().zip(&views) {
+                assert_eq!(
+                    view_a.contains(b),
+                    view_b.contains(a),
+                    "{:?} and {:?} don't see each other the same way",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn field_of_view_stops_at_walls() {
+        let visible = field_of_view((0, 0), None, (5, 1), |x, _| x == 2);
+        let expected = [(0, 0), (1, 0), (2, 0)].iter().cloned().collect();
+        assert_eq!(visible, expected);
+
+        let visible = field_of_view((5, 3), Some(2), (10, 7), |_, _| false);
+        assert!(visible.contains(&(7, 3)));
+        assert!(!visible.contains(&(8, 3)));
+    }
+
+    #[test]
+    fn reveal_only_changes_revision_when_cells_change() {
+        let mut fog = FogOfWar::new(4, 4);
+        let visible = [(1, 1), (1, 2)].iter().cloned().collect::<VisibleTiles>();
+        fog.reveal(&visible);
+        assert_eq!(fog.revision(), 1);
+        fog.reveal(&visible);
+        assert_eq!(fog.revision(), 1);
+
+        let moved = [(1, 2), (1, 3)].iter().cloned().collect::<VisibleTiles>();
+        fog.reveal(&moved);
+        assert_eq!(fog.revision(), 2);
+        assert_eq!(fog.get(1, 1), Some(Fog::Remembered));
+        assert_eq!(fog.get(1, 2), Some(Fog::Visible));
+        assert_eq!(fog.get(1, 3), Some(Fog::Visible));
+        assert_eq!(fog.get(0, 0), Some(Fog::Hidden));
+    }
+}